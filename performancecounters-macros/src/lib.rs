@@ -0,0 +1,73 @@
+//! The `#[count_events]` attribute of the `performancecounters` crate, use it through the `macros`
+//! feature of that crate rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, ItemFn, LitStr, Meta, Token};
+
+/// Count events for every call of the annotated function.
+///
+/// The calls are aggregated per function under the given name (`#[count_events(name = "parse")]`),
+/// or the name of the function when no name is given. The function is only measured when the
+/// `instrument` feature of `performancecounters` is enabled; otherwise the attribute adds no code
+/// that does anything at runtime.
+#[proc_macro_attribute]
+pub fn count_events(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = parse_macro_input!(item as ItemFn);
+
+    let args = match Punctuated::<Meta, Token![,]>::parse_terminated.parse(attr) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let mut name = LitStr::new(&function.sig.ident.to_string(), function.sig.ident.span());
+    for arg in args {
+        match arg {
+            Meta::NameValue(arg) if arg.path.is_ident("name") => match &arg.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) => name = lit.clone(),
+                other => {
+                    return syn::Error::new_spanned(other, "expected a string literal")
+                        .to_compile_error()
+                        .into()
+                }
+            },
+            other => {
+                return syn::Error::new_spanned(other, "expected `name = \"...\"`")
+                    .to_compile_error()
+                    .into()
+            }
+        }
+    }
+
+    if let Some(asyncness) = function.sig.asyncness {
+        let message = "#[count_events] counts the events of the current thread, and so cannot \
+                       measure an async fn that may move between threads";
+        return syn::Error::new_spanned(asyncness, message)
+            .to_compile_error()
+            .into();
+    }
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            static __COUNT_EVENTS_FUNCTION: ::performancecounters::Function =
+                ::performancecounters::Function::new(#name);
+            let __count_events_guard = __COUNT_EVENTS_FUNCTION.enter();
+
+            #block
+        }
+    }
+    .into()
+}
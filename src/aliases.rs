@@ -0,0 +1,398 @@
+//! Portable event names, such as `cycles` or `l1d-misses`, and the events that count them on every
+//! CPU.
+//!
+//! The table is `aliases.json`, which is embedded in the crate. Every alias has a list of events
+//! per vendor, in order of preference, so that older CPUs fall back to older events; an empty list
+//! means that the vendor's CPUs cannot count it. The `linux` list has the generic names of sysfs.
+//!
+//! The table can be replaced at runtime with `set_table`, or extended with a JSON file of the same
+//! shape in `$PERFORMANCECOUNTERS_ALIASES`, whose aliases replace the embedded ones.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+/// The aliases that `PerformanceCounters` counts, in the order of its counters
+pub(crate) const PROFILE_ALIASES: [&str; 4] =
+    ["cycles", "instructions", "branches", "branch-misses"];
+
+const EMBEDDED: &str = include_str!("aliases.json");
+
+/// The environment variable with the path of a table that extends the embedded one
+pub const ALIASES_VAR: &str = "PERFORMANCECOUNTERS_ALIASES";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Apple,
+    Intel,
+    Amd,
+}
+
+impl Vendor {
+    /// The vendor of this CPU, if it is one of the known ones
+    pub fn current() -> Option<Self> {
+        if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+            return Some(Vendor::Apple);
+        }
+        if cfg!(target_os = "macos") {
+            return Some(Vendor::Intel);
+        }
+
+        let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+        let vendor = cpuinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("vendor_id"))?
+            .trim_start_matches([' ', '\t', ':']);
+        match vendor {
+            "GenuineIntel" => Some(Vendor::Intel),
+            "AuthenticAMD" | "HygonGenuine" => Some(Vendor::Amd),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "apple" => Some(Vendor::Apple),
+            "intel" => Some(Vendor::Intel),
+            "amd" => Some(Vendor::Amd),
+            _ => None,
+        }
+    }
+}
+
+/// An event that counts an alias on some CPUs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AliasEvent {
+    pub event: String,
+    /// The CPUs that have it, for people reading the table
+    #[serde(default)]
+    pub cpus: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Alias {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub apple: Vec<AliasEvent>,
+    #[serde(default)]
+    pub intel: Vec<AliasEvent>,
+    #[serde(default)]
+    pub amd: Vec<AliasEvent>,
+    #[serde(default)]
+    pub linux: Vec<AliasEvent>,
+}
+
+impl Alias {
+    pub fn events(&self, vendor: Vendor) -> &[AliasEvent] {
+        match vendor {
+            Vendor::Apple => &self.apple,
+            Vendor::Intel => &self.intel,
+            Vendor::Amd => &self.amd,
+        }
+    }
+
+    /// The names of all events, the ones of the vendors first; the names do not clash, so they
+    /// can be tried without knowing the vendor
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.apple
+            .iter()
+            .chain(self.intel.iter())
+            .chain(self.amd.iter())
+            .chain(self.linux.iter())
+            .map(|event| event.event.as_str())
+    }
+}
+
+/// From alias to the events that count it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AliasTable {
+    pub aliases: BTreeMap<String, Alias>,
+}
+
+impl AliasTable {
+    /// The table that is embedded in the crate
+    pub fn embedded() -> Self {
+        Self::from_json(EMBEDDED).expect("the embedded alias table is valid")
+    }
+
+    pub fn from_json(json: &str) -> std::io::Result<Self> {
+        serde_json::from_str(json)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)?;
+        Self::from_json(&json)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    }
+
+    /// Add the aliases of `other`, replacing the ones with the same name
+    pub fn extend(&mut self, other: AliasTable) {
+        self.aliases.extend(other.aliases);
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&Alias> {
+        self.aliases.get(alias)
+    }
+
+    /// The names of the events of `alias`, in the order in which they should be tried
+    pub fn names(&self, alias: &str) -> Vec<&str> {
+        self.get(alias)
+            .map_or_else(Vec::new, |alias| alias.names().collect())
+    }
+}
+
+static TABLE: RwLock<Option<Arc<AliasTable>>> = RwLock::new(None);
+
+/// The table in use: the embedded one, extended with `$PERFORMANCECOUNTERS_ALIASES`, unless it
+/// was replaced with `set_table`
+pub fn table() -> Arc<AliasTable> {
+    if let Some(table) = TABLE.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return table.clone();
+    }
+
+    let mut table = TABLE.write().unwrap_or_else(|e| e.into_inner());
+    table
+        .get_or_insert_with(|| {
+            let mut table = AliasTable::embedded();
+            if let Some(path) = std::env::var_os(ALIASES_VAR) {
+                match AliasTable::load(&path) {
+                    Ok(extra) => table.extend(extra),
+                    Err(e) => eprintln!("Ignoring {ALIASES_VAR}: {e}"),
+                }
+            }
+            Arc::new(table)
+        })
+        .clone()
+}
+
+/// Replace the table, for the counters that are set up from now on
+pub fn set_table(table: AliasTable) {
+    *TABLE.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(table));
+}
+
+/// The aliases of `table` that a database cannot count although the vendor should have them, with
+/// `resolve` returning whether the database has an event
+fn missing(
+    table: &AliasTable,
+    vendor: Vendor,
+    generic: bool,
+    resolve: impl Fn(&str) -> bool,
+) -> Vec<&str> {
+    table
+        .aliases
+        .iter()
+        .filter(|(_, alias)| !alias.events(vendor).is_empty())
+        .filter(|(_, alias)| {
+            let generic = if generic { alias.linux.as_slice() } else { &[] };
+            !alias
+                .events(vendor)
+                .iter()
+                .chain(generic)
+                .any(|event| resolve(&event.event))
+        })
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// The `aliases` subcommand: `aliases [--table FILE] [--kpep FILE.plist]... [--sysfs DIR]
+/// [--json DIR] [--vendor apple|intel|amd]`. Without databases it prints the table; otherwise it
+/// prints what every alias resolves to in every database, and exits with 1 if an alias that the
+/// vendor should have does not resolve.
+pub fn main(args: &[String]) -> ! {
+    use crate::pmu::{EventDatabase, Roots};
+
+    let usage = || -> ! {
+        eprintln!(
+            "usage: aliases [--table FILE] [--kpep FILE.plist]... [--sysfs DIR] [--json DIR] [--vendor apple|intel|amd]"
+        );
+        std::process::exit(2);
+    };
+    let fail = |message: String| -> ! {
+        eprintln!("{message}");
+        std::process::exit(1);
+    };
+
+    let mut table = table();
+    let mut kpep_paths = Vec::new();
+    let mut roots = None::<Roots>;
+    let mut vendor = Vendor::current();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage()).as_str();
+        match arg.as_str() {
+            "--table" => match AliasTable::load(value()) {
+                Ok(loaded) => table = Arc::new(loaded),
+                Err(e) => fail(format!("Failed to read the alias table: {e}")),
+            },
+            "--kpep" => kpep_paths.push(value().to_owned()),
+            "--sysfs" => roots.get_or_insert_with(Roots::default).sysfs = value().into(),
+            "--json" => roots.get_or_insert_with(Roots::default).json = Some(value().into()),
+            "--vendor" => vendor = Some(Vendor::from_name(value()).unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    if kpep_paths.is_empty() && roots.is_none() {
+        for (name, alias) in table.aliases.iter() {
+            println!("{name}: {}", alias.description);
+            for (vendor, events) in [
+                ("apple", &alias.apple),
+                ("intel", &alias.intel),
+                ("amd", &alias.amd),
+                ("linux", &alias.linux),
+            ] {
+                for event in events {
+                    println!("    {vendor:<6} {:<48} {}", event.event, event.cpus);
+                }
+            }
+        }
+        std::process::exit(0);
+    }
+
+    let mut missing_count = 0;
+    let mut report = |database: &str, missing: Vec<&str>, resolved: Vec<(&str, String)>| {
+        println!("{database}");
+        for (alias, event) in resolved {
+            println!("    {alias:<16} {event}");
+        }
+        for alias in missing.iter() {
+            eprintln!("{database}: no event for {alias}");
+        }
+        missing_count += missing.len();
+    };
+
+    #[cfg(feature = "kpep")]
+    for path in kpep_paths.iter() {
+        let database = crate::kpep::Database::open(path)
+            .unwrap_or_else(|e| fail(format!("Failed to read {path}: {e}")));
+        let vendor = if database.architecture == "x86_64" {
+            Vendor::Intel
+        } else {
+            Vendor::Apple
+        };
+
+        let resolved = table
+            .aliases
+            .keys()
+            .filter_map(|alias| {
+                Some((
+                    alias.as_str(),
+                    database.resolve_alias(&table, alias)?.name.clone(),
+                ))
+            })
+            .collect();
+        let missing = missing(&table, vendor, false, |name| database.event(name).is_some());
+        report(path, missing, resolved);
+    }
+    #[cfg(not(feature = "kpep"))]
+    if !kpep_paths.is_empty() {
+        fail("reading kpep databases needs the `kpep` feature".to_owned());
+    }
+
+    if let Some(roots) = roots {
+        let database = EventDatabase::load(&roots)
+            .unwrap_or_else(|e| fail(format!("Failed to read the event database: {e}")));
+        let Some(vendor) = vendor else {
+            fail("unknown CPU vendor, use --vendor".to_owned());
+        };
+
+        let resolved = table
+            .aliases
+            .keys()
+            .filter_map(|alias| {
+                let (name, event) = database.resolve_alias(&table, alias)?;
+                Some((alias.as_str(), format!("{name} ({:#x})", event.config)))
+            })
+            .collect();
+        let missing = missing(&table, vendor, true, |name| database.resolve(name).is_ok());
+
+        let name = match &roots.json {
+            Some(json) => format!("{} + {}", roots.sysfs.display(), json.display()),
+            None => roots.sysfs.display().to_string(),
+        };
+        report(&name, missing, resolved);
+    }
+
+    std::process::exit((missing_count > 0) as i32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pmu::{EventDatabase, Roots};
+
+    fn fixture(path: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(path)
+    }
+
+    fn check_pmu(sysfs: &str, json: &str, vendor: Vendor) {
+        let table = AliasTable::embedded();
+        let roots = Roots {
+            sysfs: fixture(sysfs),
+            json: Some(fixture(json)),
+        };
+        let database = EventDatabase::load(&roots).unwrap();
+
+        for alias in table.aliases.keys() {
+            if !table.aliases[alias].events(vendor).is_empty() {
+                assert!(
+                    database.resolve_alias(&table, alias).is_some(),
+                    "{sysfs} + {json}: {alias}"
+                );
+            }
+        }
+        let missing = missing(&table, vendor, true, |name| database.resolve(name).is_ok());
+        assert!(missing.is_empty(), "{sysfs} + {json}: {missing:?}");
+    }
+
+    #[test]
+    fn every_alias_resolves_in_the_pmu_fixtures() {
+        check_pmu("pmu/sysfs", "pmu/json/haswell", Vendor::Intel);
+        check_pmu("pmu/sysfs-zen4", "pmu/json/zen4", Vendor::Amd);
+    }
+
+    #[cfg(feature = "kpep")]
+    #[test]
+    fn every_alias_resolves_in_the_kpep_fixtures() {
+        let table = AliasTable::embedded();
+        for (path, vendor) in [
+            ("kpep/cpu_100000c_2_1b588bb3.plist", Vendor::Apple),
+            ("kpep/cpu_7_8_10b282dc.plist", Vendor::Intel),
+        ] {
+            let database = crate::kpep::Database::open(fixture(path)).unwrap();
+
+            for alias in table.aliases.keys() {
+                if !table.aliases[alias].events(vendor).is_empty() {
+                    assert!(
+                        database.resolve_alias(&table, alias).is_some(),
+                        "{path}: {alias}"
+                    );
+                }
+            }
+            let missing = missing(&table, vendor, false, |name| database.event(name).is_some());
+            assert!(missing.is_empty(), "{path}: {missing:?}");
+        }
+    }
+
+    #[test]
+    fn a_table_extends_the_embedded_one() {
+        let mut table = AliasTable::embedded();
+        let extra = AliasTable::from_json(
+            r#"{ "cycles": { "linux": [{ "event": "ref-cycles" }] }, "new": {} }"#,
+        )
+        .unwrap();
+        table.extend(extra);
+
+        assert_eq!(table.names("cycles"), ["ref-cycles"]);
+        assert!(table.get("new").is_some());
+        assert!(table.get("instructions").is_some());
+    }
+}
@@ -0,0 +1,217 @@
+//! Counting the events of a process that is already running, like `perf stat -p PID`.
+//!
+//! On Linux the counters are opened for every thread of the process with `perf_event_open`. kpc
+//! can only count the current thread or whole CPUs, so on macOS the threads of the process are
+//! sampled with kperf instead (see `sampling`), and the counts are the sums of the samples. Those
+//! miss the events of a thread since its last sample, which is at most `SAMPLE_PERIOD` ago.
+
+use std::time::{Duration, Instant};
+
+use crate::interval;
+use crate::table::{format_counters, Style};
+use crate::{EventCount, PerformanceCounters};
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Stop after this long; otherwise count until Ctrl-C or until the process exits
+    pub duration: Option<Duration>,
+    /// Report the counters of every interval of this length while counting
+    pub interval: Option<Duration>,
+}
+
+/// How often the threads of the process are sampled on macOS
+#[cfg(not(target_os = "linux"))]
+pub const SAMPLE_PERIOD: Duration = Duration::from_millis(10);
+
+/// Count the events of all threads of the process `pid`. With an interval, `on_interval` is called
+/// with the time since attaching and the counts of every interval.
+pub fn attach(
+    pid: i32,
+    options: &Options,
+    mut on_interval: impl FnMut(Duration, PerformanceCounters),
+) -> std::io::Result<EventCount> {
+    #[cfg(target_os = "linux")]
+    {
+        let threads = open_threads(pid)?;
+        let read = || -> std::io::Result<PerformanceCounters> {
+            let mut total = PerformanceCounters::default();
+            for counters in threads.iter() {
+                total += counters.read()?;
+            }
+            Ok(total)
+        };
+
+        let start = Instant::now();
+        for counters in threads.iter() {
+            counters.enable()?;
+        }
+
+        let mut previous = PerformanceCounters::default();
+        interval::run(
+            start,
+            options.interval,
+            options.duration,
+            || !interval::process_alive(pid),
+            |time| {
+                let current = read()?;
+                on_interval(time, current - previous);
+                previous = current;
+                Ok(())
+            },
+        )?;
+
+        Ok(EventCount::from_counters(read()?, start.elapsed()))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        use std::collections::HashSet;
+
+        use crate::sampling::{self, KperfSession, TraceDecoder, DRAIN_PERIOD};
+
+        let collector = crate::session::Session::global()?.collector();
+        let sampling_options = sampling::Options {
+            period: SAMPLE_PERIOD,
+            every_thread: true,
+            ..sampling::Options::default()
+        };
+
+        let start = Instant::now();
+        let session = KperfSession::start(collector, pid, &sampling_options)?;
+        let mut decoder = TraceDecoder::default();
+        let mut entries = Vec::new();
+        let mut samples = Vec::new();
+
+        // the first sample of a thread has its counts since the thread started
+        let mut sampled = HashSet::new();
+        let mut total = PerformanceCounters::default();
+        let mut current = PerformanceCounters::default();
+        let mut next_interval = options.interval;
+
+        let mut drain = |time: Duration| -> std::io::Result<()> {
+            crate::kdebug::read(&mut entries, KperfSession::BUFFER_ENTRIES)?;
+            decoder.decode(collector, &entries, &mut samples)?;
+            for sample in samples.drain(..) {
+                if sampled.insert(sample.thread) {
+                    continue;
+                }
+                total += sample.counters;
+                current += sample.counters;
+            }
+
+            // the trace is drained more often than the intervals, or it fills up
+            if let (Some(deadline), Some(interval)) = (next_interval, options.interval) {
+                if time >= deadline {
+                    on_interval(time, std::mem::take(&mut current));
+                    next_interval = Some(deadline + interval);
+                }
+            }
+            Ok(())
+        };
+
+        interval::run(
+            start,
+            Some(DRAIN_PERIOD),
+            options.duration,
+            || !interval::process_alive(pid),
+            &mut drain,
+        )?;
+        drain(start.elapsed())?;
+        drop(session);
+
+        Ok(EventCount::from_counters(total, start.elapsed()))
+    }
+}
+
+/// Open counters for every thread of the process. Threads that are created later are counted by
+/// the counters of the thread that created them.
+#[cfg(target_os = "linux")]
+fn open_threads(pid: i32) -> std::io::Result<Vec<crate::linux::PerfCounters>> {
+    use crate::linux::{threads, PerfCounters, Target};
+
+    let mut counters = Vec::new();
+
+    for tid in threads(pid)? {
+        let target = Target {
+            pid: tid,
+            cpu: -1,
+            inherit: true,
+            enable_on_exec: false,
+        };
+
+        match PerfCounters::open(target) {
+            Ok(thread) => counters.push(thread),
+            // the thread exited in the meantime
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(counters)
+}
+
+/// The `attach` subcommand: `attach -p PID [-m MODIFIERS] [--duration SECONDS] [--interval
+/// MILLISECONDS]`, where the modifiers are the modes to count in, such as `u` (see `modes`)
+pub fn main(args: &[String]) -> ! {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: attach -p|--pid PID [-m|--modes u|k|uk] [--duration SECONDS] [--interval MILLISECONDS]"
+        );
+        std::process::exit(2);
+    };
+
+    let mut pid = None;
+    let mut options = Options::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-p" | "--pid" => pid = Some(value.parse().unwrap_or_else(|_| usage())),
+            "-m" | "--modes" => crate::modes::set_modes(value.parse().unwrap_or_else(|_| usage()))
+                .unwrap_or_else(|_| usage()),
+            "--duration" => {
+                let seconds: f64 = value.parse().unwrap_or_else(|_| usage());
+                // negative, infinite and NaN durations do not convert
+                let duration = Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage());
+                if duration.is_zero() {
+                    usage();
+                }
+                options.duration = Some(duration);
+            }
+            "--interval" => match value.parse() {
+                Ok(milliseconds) if milliseconds > 0 => {
+                    options.interval = Some(Duration::from_millis(milliseconds))
+                }
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    let Some(pid) = pid else { usage() };
+
+    crate::interval::catch_interrupt();
+
+    let on_interval = |time: Duration, counters: PerformanceCounters| {
+        let counters = format_counters(&counters, Style::default());
+        eprintln!("{:>10.3}s{counters}", time.as_secs_f64());
+    };
+
+    match attach(pid, &options, on_interval) {
+        Ok(count) => {
+            let counters = PerformanceCounters::from_event_count(count);
+            eprintln!();
+            eprintln!(
+                " Performance counter stats for process id '{pid}' ({:.3}s):",
+                count.elapsed.as_secs_f64()
+            );
+            eprintln!("{:>11}{}", "", format_counters(&counters, Style::default()));
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Failed to attach to process {pid}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Named baselines on disk, and detecting regressions against them.
+//!
+//! A baseline is the JSON export of a set of benchmarks, stored as
+//! `<target dir>/performancecounters/<name>.json`.
+
+use std::path::{Path, PathBuf};
+
+use crate::export::{self, Benchmark};
+use crate::table::{format_value, Style};
+use crate::Counter;
+
+/// The directory in which baselines are stored: `performancecounters` in the cargo target directory
+pub fn default_dir() -> PathBuf {
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    Path::new(&target).join("performancecounters")
+}
+
+/// The file of the baseline `name`, which has to be a plain file name so that it stays in `dir`
+fn path(dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    if matches!(name, "" | "." | "..") || name.contains(['/', '\\']) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{name:?} is not a valid baseline name"),
+        ));
+    }
+    Ok(dir.join(format!("{name}.json")))
+}
+
+pub fn save(dir: &Path, name: &str, benchmarks: &[Benchmark]) -> std::io::Result<()> {
+    let path = path(dir, name)?;
+    std::fs::create_dir_all(dir)?;
+
+    let file = std::fs::File::create(path)?;
+    export::write_json(&mut std::io::BufWriter::new(file), benchmarks)
+}
+
+pub fn load(dir: &Path, name: &str) -> std::io::Result<Vec<Benchmark>> {
+    let file = std::fs::File::open(path(dir, name)?)?;
+    export::read_json(std::io::BufReader::new(file))
+}
+
+/// The change of the mean of one counter of one benchmark
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub benchmark: String,
+    pub counter: Counter,
+    pub baseline: f64,
+    pub current: f64,
+    /// Whether the counter increased by more than the threshold, for a counter that gates
+    pub regression: bool,
+}
+
+impl Change {
+    /// The change relative to the baseline, in percent
+    pub fn percentage(&self) -> f64 {
+        if self.baseline == 0.0 {
+            if self.current == 0.0 {
+                0.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            100.0 * (self.current - self.baseline) / self.baseline
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    pub changes: Vec<Change>,
+    /// Benchmarks that are not in the baseline
+    pub new: Vec<String>,
+}
+
+impl Comparison {
+    pub fn has_regressions(&self) -> bool {
+        self.changes.iter().any(|change| change.regression)
+    }
+}
+
+/// Compare the means of every counter against the baseline. An increase of more than `threshold`
+/// percent of one of the `gate` counters is a regression.
+pub fn compare(
+    baseline: &[Benchmark],
+    current: &[Benchmark],
+    threshold: f64,
+    gate: &[Counter],
+) -> Comparison {
+    let mut comparison = Comparison::default();
+
+    for benchmark in current {
+        let Some(old) = baseline.iter().find(|old| old.name == benchmark.name) else {
+            comparison.new.push(benchmark.name.clone());
+            continue;
+        };
+
+        for counter in Counter::ALL {
+            let mut change = Change {
+                benchmark: benchmark.name.clone(),
+                counter,
+                baseline: counter.get(&old.run.mean),
+                current: counter.get(&benchmark.run.mean),
+                regression: false,
+            };
+
+            change.regression = gate.contains(&counter) && change.percentage() > threshold;
+            comparison.changes.push(change);
+        }
+    }
+
+    comparison
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let style = Style::default();
+
+        for change in self.changes.iter() {
+            writeln!(
+                f,
+                "{:<24} {:>16} {:>+9.2}% {:>18} -> {:<18}{}",
+                change.benchmark,
+                change.counter.name(),
+                change.percentage(),
+                format_value(change.baseline, style),
+                format_value(change.current, style),
+                if change.regression { " REGRESSION" } else { "" },
+            )?;
+        }
+
+        for name in self.new.iter() {
+            writeln!(f, "{name:<24} not in the baseline")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::Throughput;
+
+use crate::{read_thread_values, thread_delta, Counter, CounterValues};
+
+/// A criterion `Measurement` that measures one of the counters instead of wall time.
+///
+/// ```ignore
+/// fn instructions() -> Criterion<CounterMeasurement> {
+///     Criterion::default().with_measurement(CounterMeasurement::new(Counter::Instructions))
+/// }
+///
+/// criterion_group! {
+///     name = benches;
+///     config = instructions();
+///     targets = bench_sort
+/// }
+/// ```
+pub struct CounterMeasurement {
+    counter: Counter,
+}
+
+impl CounterMeasurement {
+    pub fn new(counter: Counter) -> Self {
+        Self { counter }
+    }
+}
+
+impl Default for CounterMeasurement {
+    fn default() -> Self {
+        Self::new(Counter::Instructions)
+    }
+}
+
+impl Measurement for CounterMeasurement {
+    type Intermediate = std::io::Result<CounterValues>;
+    type Value = f64;
+
+    #[inline(always)]
+    fn start(&self) -> Self::Intermediate {
+        read_thread_values()
+    }
+
+    #[inline(always)]
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        // criterion has no way to report a failed measurement, and zeros would look like one
+        let end = read_thread_values();
+        let counters = thread_delta(&start, &end).expect("the counters could not be read");
+        self.counter.get(&counters)
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0.0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl CounterMeasurement {
+    /// The unit for 1, 1e3, 1e6 and 1e9 events
+    const fn units(&self) -> [&'static str; 4] {
+        match self.counter {
+            Counter::Cycles => ["cycles", "Kcycles", "Mcycles", "Gcycles"],
+            Counter::Instructions => ["insts", "Kinsts", "Minsts", "Ginsts"],
+            Counter::Branches => ["branches", "Kbranches", "Mbranches", "Gbranches"],
+            Counter::MissedBranches => ["misses", "Kmisses", "Mmisses", "Gmisses"],
+        }
+    }
+
+    /// The unit for events per byte and per element
+    const fn throughput_units(&self) -> [&'static str; 2] {
+        match self.counter {
+            Counter::Cycles => ["cycles/B", "cycles/elem"],
+            Counter::Instructions => ["insts/B", "insts/elem"],
+            Counter::Branches => ["branches/B", "branches/elem"],
+            Counter::MissedBranches => ["misses/B", "misses/elem"],
+        }
+    }
+}
+
+impl ValueFormatter for CounterMeasurement {
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        let units = self.units();
+
+        let (factor, unit) = if typical_value < 1e3 {
+            (1.0, units[0])
+        } else if typical_value < 1e6 {
+            (1e-3, units[1])
+        } else if typical_value < 1e9 {
+            (1e-6, units[2])
+        } else {
+            (1e-9, units[3])
+        };
+
+        for value in values {
+            *value *= factor;
+        }
+
+        unit
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let units = self.throughput_units();
+
+        let (amount, unit) = match *throughput {
+            Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => (bytes, units[0]),
+            Throughput::Elements(elements) => (elements, units[1]),
+        };
+
+        for value in values {
+            *value /= amount as f64;
+        }
+
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        // no scaling is needed
+        self.units()[0]
+    }
+}
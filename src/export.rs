@@ -0,0 +1,203 @@
+//! Machine-readable output of measurements, for dashboards and notebooks.
+//!
+//! JSON contains everything: the metadata, the raw per-iteration samples and the aggregates.
+//! CSV comes in two shapes: one row per sample, or one row per benchmark.
+
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::{Reader, Session};
+use crate::{collect_samples, Counter, EventCollector, EventCount, Run};
+
+/// Where and when a benchmark was measured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub os: String,
+    pub arch: String,
+    /// The CPU as reported by the PMC database, when it could be loaded
+    pub cpu: Option<String>,
+    pub repeat: usize,
+}
+
+/// The raw samples of a benchmark together with their aggregates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Benchmark {
+    pub name: String,
+    pub metadata: Metadata,
+    pub samples: Vec<EventCount>,
+    pub run: Run,
+}
+
+impl Benchmark {
+    pub fn measure(
+        collector: &mut EventCollector,
+        name: &str,
+        repeat: usize,
+        f: impl Fn(),
+    ) -> Self {
+        let samples = collect_samples(collector, repeat, f);
+        Self::from_samples(name, repeat, collector.cpu(), samples)
+    }
+
+    /// Like `measure`, counting with a reader of the global session, which works on every OS
+    pub fn measure_with(
+        reader: &mut Reader,
+        name: &str,
+        repeat: usize,
+        f: impl Fn(),
+    ) -> std::io::Result<Self> {
+        let mut samples = Vec::with_capacity(repeat);
+        for _ in 0..repeat {
+            let ((), sample) = reader.count(&f)?;
+            samples.push(sample);
+        }
+
+        let session = Session::global()?;
+        Ok(Self::from_samples(name, repeat, session.cpu(), samples))
+    }
+
+    fn from_samples(
+        name: &str,
+        repeat: usize,
+        cpu: Option<&str>,
+        samples: Vec<EventCount>,
+    ) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Benchmark {
+            name: name.to_string(),
+            metadata: Metadata {
+                timestamp,
+                os: std::env::consts::OS.to_string(),
+                arch: std::env::consts::ARCH.to_string(),
+                cpu: cpu.map(str::to_string),
+                repeat,
+            },
+            run: Run::from_samples(&samples),
+            samples,
+        }
+    }
+}
+
+/// The serialized form of `EventCount`: named counters instead of the raw array
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Sample {
+    elapsed_ns: u64,
+    cycles: u64,
+    instructions: u64,
+    branches: u64,
+    missed_branches: u64,
+}
+
+impl From<EventCount> for Sample {
+    fn from(event_count: EventCount) -> Self {
+        Self {
+            elapsed_ns: event_count.elapsed.as_nanos() as u64,
+            cycles: event_count.cycles(),
+            instructions: event_count.instructions(),
+            branches: event_count.branches(),
+            missed_branches: event_count.missed_branches(),
+        }
+    }
+}
+
+impl From<Sample> for EventCount {
+    fn from(sample: Sample) -> Self {
+        let mut event_count = EventCount {
+            elapsed: core::time::Duration::from_nanos(sample.elapsed_ns),
+            ..EventCount::default()
+        };
+
+        event_count.event_counts[0] = sample.cycles;
+        event_count.event_counts[1] = sample.instructions;
+        event_count.event_counts[2] = sample.missed_branches;
+        event_count.event_counts[4] = sample.branches;
+
+        event_count
+    }
+}
+
+pub fn write_json(w: &mut impl Write, benchmarks: &[Benchmark]) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(&mut *w, benchmarks)?;
+    writeln!(w)
+}
+
+pub fn read_json(r: impl std::io::Read) -> std::io::Result<Vec<Benchmark>> {
+    Ok(serde_json::from_reader(r)?)
+}
+
+/// One row per sample
+pub fn write_samples_csv(w: &mut impl Write, benchmarks: &[Benchmark]) -> std::io::Result<()> {
+    writeln!(
+        w,
+        "name,iteration,elapsed_ns,cycles,instructions,branches,missed_branches"
+    )?;
+
+    for benchmark in benchmarks {
+        let name = csv_field(&benchmark.name);
+        for (iteration, sample) in benchmark.samples.iter().enumerate() {
+            writeln!(
+                w,
+                "{name},{iteration},{},{},{},{},{}",
+                sample.elapsed.as_nanos(),
+                sample.cycles(),
+                sample.instructions(),
+                sample.branches(),
+                sample.missed_branches(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// One row per benchmark, with the aggregates of every counter
+pub fn write_runs_csv(w: &mut impl Write, benchmarks: &[Benchmark]) -> std::io::Result<()> {
+    write!(w, "name,repeat")?;
+    for counter in Counter::ALL {
+        let name = counter.name();
+        write!(
+            w,
+            ",{name}_mean,{name}_minimum,{name}_maximum,{name}_standard_deviation"
+        )?;
+    }
+    writeln!(w)?;
+
+    for benchmark in benchmarks {
+        write!(
+            w,
+            "{},{}",
+            csv_field(&benchmark.name),
+            benchmark.samples.len()
+        )?;
+
+        let run = &benchmark.run;
+        for counter in Counter::ALL {
+            write!(
+                w,
+                ",{},{},{},{}",
+                counter.get(&run.mean),
+                counter.get(&run.minimum),
+                counter.get(&run.maximum),
+                counter.get(&run.standard_deviation)
+            )?;
+        }
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+/// Quote a field if it contains characters that are special in CSV
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Mutex;
+#[cfg(feature = "instrument")]
+use std::sync::Once;
+
+use crate::{Aggregate, Region};
+
+/// All functions that have been called at least once, in the order of their first call
+static FUNCTIONS: Mutex<Vec<&'static Function>> = Mutex::new(Vec::new());
+
+/// The measurements of a function annotated with `#[count_events]`.
+///
+/// Every annotated function gets its own `static`, so that measuring a call only locks the
+/// aggregate of that function.
+pub struct Function {
+    name: &'static str,
+    aggregate: Mutex<Aggregate>,
+    #[cfg(feature = "instrument")]
+    registered: Once,
+}
+
+impl Function {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            aggregate: Mutex::new(Aggregate::new()),
+            #[cfg(feature = "instrument")]
+            registered: Once::new(),
+        }
+    }
+
+    /// Start measuring a call; the measurement ends when the returned guard is dropped
+    #[inline(always)]
+    pub fn enter(&'static self) -> FunctionGuard {
+        #[cfg(feature = "instrument")]
+        {
+            self.registered.call_once(|| {
+                FUNCTIONS
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(self)
+            });
+
+            FunctionGuard {
+                function: self,
+                start_clock: std::time::Instant::now(),
+                start: crate::read_thread_values(),
+            }
+        }
+
+        #[cfg(not(feature = "instrument"))]
+        FunctionGuard {}
+    }
+}
+
+/// Guard for a call of a function annotated with `#[count_events]`. Without the `instrument`
+/// feature this guard is empty, and the annotation costs nothing.
+pub struct FunctionGuard {
+    #[cfg(feature = "instrument")]
+    function: &'static Function,
+    #[cfg(feature = "instrument")]
+    start_clock: std::time::Instant,
+    #[cfg(feature = "instrument")]
+    start: std::io::Result<crate::CounterValues>,
+}
+
+#[cfg(feature = "instrument")]
+impl Drop for FunctionGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let end = crate::read_thread_values();
+        let elapsed = self.start_clock.elapsed();
+
+        // a call without counters is left out, rather than counted as zero events
+        let Some(delta) = crate::thread_delta(&self.start, &end) else {
+            return;
+        };
+
+        let mut aggregate = self
+            .function
+            .aggregate
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        aggregate.push_counters(delta, elapsed);
+    }
+}
+
+/// The measurements of all functions annotated with `#[count_events]` so far
+pub fn function_report() -> Vec<Region> {
+    let functions = FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner());
+
+    functions
+        .iter()
+        .map(|function| {
+            let aggregate = function.aggregate.lock().unwrap_or_else(|e| e.into_inner());
+
+            Region {
+                name: function.name,
+                calls: aggregate.count(),
+                elapsed: aggregate.elapsed(),
+                run: aggregate.run(),
+            }
+        })
+        .collect()
+}
+
+/// Print the measurements of all functions annotated with `#[count_events]` so far
+pub fn print_function_report() {
+    for function in function_report() {
+        eprintln!("{:#?}", function);
+    }
+}
@@ -0,0 +1,74 @@
+//! Counting the events of a future, for async code where a task moves between threads.
+//!
+//! The counters of the thread that polls the future are read at the start and at the end of every
+//! `poll`, and the deltas are summed, so the count only includes the work of the future itself and
+//! not of the tasks that the executor runs in between. This works with any executor.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{read_thread_values, thread_delta, EventCount, PerformanceCounters};
+
+/// Adds `count_events` to every future
+pub trait CountEvents: Future + Sized {
+    /// Count the events of every poll of this future, and return them together with its output
+    fn count_events(self) -> Counted<Self> {
+        Counted {
+            future: self,
+            counters: PerformanceCounters::default(),
+            elapsed: Duration::ZERO,
+            polls: 0,
+        }
+    }
+}
+
+impl<F: Future> CountEvents for F {}
+
+/// The future returned by `count_events`
+pub struct Counted<F> {
+    future: F,
+    counters: PerformanceCounters,
+    /// The time spent in `poll`, rather than the time until the future completed
+    elapsed: Duration,
+    polls: usize,
+}
+
+impl<F> Counted<F> {
+    /// How often the future was polled so far
+    pub fn polls(&self) -> usize {
+        self.polls
+    }
+}
+
+impl<F: Future> Future for Counted<F> {
+    type Output = (F::Output, EventCount);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `future` is pinned whenever `self` is: it is never moved out of `self`, and
+        // `Counted` only implements `Unpin` when `F` does
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let start_clock = Instant::now();
+        let start = read_thread_values();
+
+        let poll = future.poll(cx);
+
+        let end = read_thread_values();
+        this.elapsed += start_clock.elapsed();
+        // a poll without counters is left out of the counts, after a warning
+        if let Some(delta) = thread_delta(&start, &end) {
+            this.counters += delta;
+        }
+        this.polls += 1;
+
+        poll.map(|output| {
+            (
+                output,
+                EventCount::from_counters(this.counters, this.elapsed),
+            )
+        })
+    }
+}
@@ -0,0 +1,270 @@
+//! A benchmark harness for `harness = false` targets.
+//!
+//! ```ignore
+//! fn sort() {
+//!     let mut v = std::hint::black_box(b"kperf".to_vec());
+//!     v.sort();
+//! }
+//!
+//! performancecounters::benchmark_main!(sort);
+//! ```
+//!
+//! The generated `main` accepts a name filter (`--bench sort`, or just `sort`), the number of
+//! repetitions (`--repeat 100`) and the output format (`--format table`, `markdown`, `json`,
+//! `csv` or `csv-samples`). With `--scale`, the tables show large values in K/M/G/T.
+//!
+//! Results can be saved as a named baseline (`--save-baseline main`) and compared against one
+//! (`--baseline main`); saving over an existing baseline compares against it first. When one of
+//! the gating counters (`--gate instructions,branches`, by default instructions) increased by more
+//! than the threshold (`--threshold 1.0`, in percent), the process exits with status 1.
+
+use std::path::PathBuf;
+
+use crate::export::{self, Benchmark};
+use crate::session::Session;
+use crate::table::{self, Style};
+use crate::{baseline, Counter};
+
+/// A benchmark registered with `benchmark_main!`
+#[derive(Clone, Copy)]
+pub struct Bench {
+    pub name: &'static str,
+    pub f: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Markdown,
+    Json,
+    /// One row per benchmark
+    Csv,
+    /// One row per sample
+    CsvSamples,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub filter: Option<String>,
+    pub repeat: usize,
+    pub format: Format,
+    /// Scale the values of the tables to K/M/G/T
+    pub scale: bool,
+    /// Compare against this baseline
+    pub baseline: Option<String>,
+    /// Save the results under this baseline
+    pub save_baseline: Option<String>,
+    pub baseline_dir: PathBuf,
+    /// The increase in percent beyond which a gating counter is a regression
+    pub threshold: f64,
+    pub gate: Vec<Counter>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            repeat: 100,
+            format: Format::Table,
+            scale: false,
+            baseline: None,
+            save_baseline: None,
+            baseline_dir: baseline::default_dir(),
+            threshold: 1.0,
+            gate: vec![Counter::Instructions],
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // cargo passes a bare `--bench` to every `harness = false` target
+                "--bench" => {
+                    if let Some(filter) = args.next_if(|arg| !arg.starts_with('-')) {
+                        options.filter = Some(filter);
+                    }
+                }
+                "--repeat" => {
+                    let value = args.next().ok_or("--repeat needs a value")?;
+                    options.repeat = match value.parse() {
+                        Ok(0) | Err(_) => return Err(format!("invalid --repeat value: {value}")),
+                        Ok(repeat) => repeat,
+                    };
+                }
+                "--format" => {
+                    let value = args.next().ok_or("--format needs a value")?;
+                    options.format = match value.as_str() {
+                        "table" => Format::Table,
+                        "markdown" => Format::Markdown,
+                        "json" => Format::Json,
+                        "csv" => Format::Csv,
+                        "csv-samples" => Format::CsvSamples,
+                        _ => return Err(format!("unknown --format value: {value}")),
+                    };
+                }
+                "--scale" => options.scale = true,
+                "--baseline" => {
+                    let value = args.next().ok_or("--baseline needs a name")?;
+                    options.baseline = Some(value);
+                }
+                "--save-baseline" => {
+                    let value = args.next().ok_or("--save-baseline needs a name")?;
+                    options.save_baseline = Some(value);
+                }
+                "--threshold" => {
+                    let value = args.next().ok_or("--threshold needs a value")?;
+                    options.threshold = match value.parse() {
+                        Ok(threshold) if threshold >= 0.0 => threshold,
+                        _ => return Err(format!("invalid --threshold value: {value}")),
+                    };
+                }
+                "--gate" => {
+                    let value = args.next().ok_or("--gate needs a list of counters")?;
+                    options.gate = value
+                        .split(',')
+                        .map(|name| {
+                            Counter::from_name(name).ok_or(format!("unknown counter: {name}"))
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option: {arg}")),
+                _ => options.filter = Some(arg),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// Run the benchmarks that match the filter, and print their results, or fail if there are no
+/// counters
+pub fn run(benches: &[Bench], options: &Options) -> std::io::Result<Vec<Benchmark>> {
+    let mut reader = Session::global()?.reader()?;
+
+    let results: Vec<_> = benches
+        .iter()
+        .filter(|bench| match &options.filter {
+            None => true,
+            Some(filter) => bench.name.contains(filter.as_str()),
+        })
+        .map(|bench| Benchmark::measure_with(&mut reader, bench.name, options.repeat, bench.f))
+        .collect::<std::io::Result<_>>()?;
+
+    let mut stdout = std::io::stdout().lock();
+    let written = match options.format {
+        Format::Table => print_tables(
+            &results,
+            Style {
+                scale: options.scale,
+                ..Style::for_stdout()
+            },
+        ),
+        Format::Markdown => print_tables(
+            &results,
+            Style {
+                markdown: true,
+                scale: options.scale,
+                ..Style::default()
+            },
+        ),
+        Format::Json => export::write_json(&mut stdout, &results),
+        Format::Csv => export::write_runs_csv(&mut stdout, &results),
+        Format::CsvSamples => export::write_samples_csv(&mut stdout, &results),
+    };
+
+    if let Err(e) = written {
+        eprintln!("Failed to write results: {e}");
+    }
+
+    Ok(results)
+}
+
+/// The entry point of the `main` generated by `benchmark_main!`
+pub fn main(benches: &[Bench]) {
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "usage: [--bench] [FILTER] [--repeat N] [--format table|markdown|json|csv|csv-samples] [--scale]"
+            );
+            eprintln!(
+                "       [--baseline NAME] [--save-baseline NAME] [--threshold PERCENT] [--gate COUNTERS]"
+            );
+            std::process::exit(2);
+        }
+    };
+
+    let results = match run(benches, &options) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Failed to count events: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut regressed = false;
+
+    // saving over an existing baseline compares against it, like criterion does
+    if let Some(name) = options.baseline.as_ref().or(options.save_baseline.as_ref()) {
+        match baseline::load(&options.baseline_dir, name) {
+            Ok(old) => {
+                let comparison =
+                    baseline::compare(&old, &results, options.threshold, &options.gate);
+                eprintln!("Compared to baseline '{name}':");
+                eprint!("{comparison}");
+                regressed = comparison.has_regressions();
+            }
+            Err(e) if options.baseline.is_some() => {
+                eprintln!("Failed to load baseline '{name}': {e}");
+                std::process::exit(2);
+            }
+            Err(_) => { /* nothing saved under this name yet */ }
+        }
+    }
+
+    if let Some(name) = &options.save_baseline {
+        if let Err(e) = baseline::save(&options.baseline_dir, name, &results) {
+            eprintln!("Failed to save baseline '{name}': {e}");
+            std::process::exit(2);
+        }
+    }
+
+    if regressed {
+        std::process::exit(1);
+    }
+}
+
+fn print_tables(results: &[Benchmark], style: Style) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout().lock();
+    for benchmark in results {
+        let table = table::format_run(
+            &benchmark.name,
+            benchmark.samples.len(),
+            &benchmark.run,
+            style,
+        );
+        writeln!(stdout, "{table}")?;
+    }
+
+    Ok(())
+}
+
+/// Generate a `main` that runs the given benchmark functions with the harness
+#[macro_export]
+macro_rules! benchmark_main {
+    ( $( $bench:path ),* $(,)? ) => {
+        fn main() {
+            $crate::harness::main(&[
+                $( $crate::harness::Bench { name: stringify!($bench), f: $bench }, )*
+            ]);
+        }
+    };
+}
@@ -0,0 +1,67 @@
+//! The loop shared by the modes that count for a while rather than around a closure: count until a
+//! duration has passed, until Ctrl-C, or until the caller stops, and report every interval.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set by the SIGINT handler from `catch_interrupt`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Make Ctrl-C stop the loop of `run` instead of the process, so that the totals still get printed
+pub(crate) fn catch_interrupt() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_sigint(_: libc::c_int) {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+        }
+
+        let handler: extern "C" fn(libc::c_int) = on_sigint;
+        unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    }
+}
+
+/// Call `on_interval` with the time since `start` every `interval`, until `duration` has passed,
+/// Ctrl-C is pressed, or `done` returns true
+pub(crate) fn run(
+    start: Instant,
+    interval: Option<Duration>,
+    duration: Option<Duration>,
+    mut done: impl FnMut() -> bool,
+    mut on_interval: impl FnMut(Duration) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut next_interval = interval.map(|interval| start + interval);
+
+    loop {
+        let now = Instant::now();
+
+        if let (Some(deadline), Some(interval)) = (next_interval, interval) {
+            if now >= deadline {
+                on_interval(now - start)?;
+                next_interval = Some(deadline + interval);
+            }
+        }
+
+        if INTERRUPTED.load(Ordering::Relaxed)
+            || duration.is_some_and(|duration| now - start >= duration)
+            || done()
+        {
+            return Ok(());
+        }
+
+        // wake up often enough to notice Ctrl-C and `done`
+        let mut sleep = Duration::from_millis(100);
+        if let Some(deadline) = next_interval {
+            sleep = sleep.min(deadline.saturating_duration_since(now));
+        }
+        if let Some(duration) = duration {
+            sleep = sleep.min((start + duration).saturating_duration_since(now));
+        }
+        std::thread::sleep(sleep);
+    }
+}
+
+/// Whether the process `pid` still exists, for stopping when it exits
+pub(crate) fn process_alive(pid: i32) -> bool {
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
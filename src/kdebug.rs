@@ -0,0 +1,158 @@
+//! The kdebug trace buffer, into which kperf writes its samples. Reverse engineered from xnu
+//! `bsd/sys/kdebug.h` and `osfmk/kperf/buffer.h`; needs root.
+
+// sysctl names under `CTL_KERN, KERN_KDEBUG`
+const KERN_KDEBUG: libc::c_int = 24;
+const KERN_KDENABLE: libc::c_int = 3;
+const KERN_KDSETBUF: libc::c_int = 4;
+const KERN_KDSETUP: libc::c_int = 6;
+const KERN_KDREMOVE: libc::c_int = 7;
+const KERN_KDSETREG: libc::c_int = 8;
+const KERN_KDREADTR: libc::c_int = 10;
+
+/// `kd_regtype.type`: only trace the debug ids in `value1` to `value4`
+const KDBG_VALCHECK: u32 = 0x0020_0000;
+
+// Debug ids: class (8 bits), subclass (8 bits), code (14 bits) and function (2 bits)
+const DBG_PERF: u32 = 37;
+const PERF_CALLSTACK: u32 = 2;
+const PERF_KPC: u32 = 6;
+
+const fn event_id(class: u32, subclass: u32, code: u32) -> u32 {
+    ((class & 0xff) << 24) | ((subclass & 0xff) << 16) | ((code & 0x3fff) << 2)
+}
+
+/// The counters of a thread, 4 per entry: `DBG_FUNC_START` on the first and `DBG_FUNC_END` on
+/// the last entry
+pub(crate) const PERF_KPC_DATA_THREAD: u32 = event_id(DBG_PERF, PERF_KPC, 8);
+/// The header of a user space call stack: `arg1` flags, `arg2` the number of frames
+pub(crate) const PERF_CS_UHDR: u32 = event_id(DBG_PERF, PERF_CALLSTACK, 6);
+/// The frames of a user space call stack, 4 per entry
+pub(crate) const PERF_CS_UDATA: u32 = event_id(DBG_PERF, PERF_CALLSTACK, 4);
+
+pub(crate) const DBG_FUNC_START: u32 = 1;
+pub(crate) const DBG_FUNC_END: u32 = 2;
+const DBG_FUNC_MASK: u32 = 3;
+
+/// An entry of the trace buffer (size: 64 bytes on 64 bit OS)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct KdBuf {
+    /// In mach absolute time units
+    pub timestamp: u64,
+    pub arg1: usize,
+    pub arg2: usize,
+    pub arg3: usize,
+    pub arg4: usize,
+    /// The thread id
+    pub arg5: usize,
+    pub debugid: u32,
+    pub cpuid: u32,
+    pub unused: usize,
+}
+
+impl KdBuf {
+    /// The debug id without the function bits
+    pub fn event(&self) -> u32 {
+        self.debugid & !DBG_FUNC_MASK
+    }
+
+    pub fn function(&self) -> u32 {
+        self.debugid & DBG_FUNC_MASK
+    }
+
+    pub fn args(&self) -> [u64; 4] {
+        [self.arg1, self.arg2, self.arg3, self.arg4].map(|arg| arg as u64)
+    }
+}
+
+/// `kd_regtype`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct KdRegtype {
+    type_: u32,
+    value1: u32,
+    value2: u32,
+    value3: u32,
+    value4: u32,
+}
+
+fn sysctl(
+    name: &[libc::c_int],
+    old: *mut libc::c_void,
+    old_len: &mut usize,
+) -> std::io::Result<()> {
+    let mut mib = vec![libc::CTL_KERN, KERN_KDEBUG];
+    mib.extend_from_slice(name);
+
+    let old_len = if old.is_null() {
+        core::ptr::null_mut()
+    } else {
+        old_len as *mut usize
+    };
+
+    match unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as u32,
+            old,
+            old_len,
+            core::ptr::null_mut(),
+            0,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Trace the given events (at most 4) into a fresh buffer of `entries` entries
+pub(crate) fn start(entries: usize, events: &[u32]) -> std::io::Result<()> {
+    let mut unused = 0;
+
+    // a buffer left over from an earlier trace is not an error
+    let _ = sysctl(&[KERN_KDREMOVE], core::ptr::null_mut(), &mut unused);
+    sysctl(
+        &[KERN_KDSETBUF, entries as libc::c_int],
+        core::ptr::null_mut(),
+        &mut unused,
+    )?;
+    sysctl(&[KERN_KDSETUP], core::ptr::null_mut(), &mut unused)?;
+
+    let value = |i: usize| events.get(i).copied().unwrap_or_default();
+    let mut filter = KdRegtype {
+        type_: KDBG_VALCHECK,
+        value1: value(0),
+        value2: value(1),
+        value3: value(2),
+        value4: value(3),
+    };
+    let mut len = core::mem::size_of_val(&filter);
+    sysctl(
+        &[KERN_KDSETREG],
+        (&mut filter as *mut KdRegtype).cast(),
+        &mut len,
+    )?;
+
+    sysctl(&[KERN_KDENABLE, 1], core::ptr::null_mut(), &mut unused)
+}
+
+/// Move the entries that were traced since the last call into `buffer`
+pub(crate) fn read(buffer: &mut Vec<KdBuf>, capacity: usize) -> std::io::Result<()> {
+    buffer.clear();
+    buffer.resize(capacity, KdBuf::default());
+
+    // bytes on the way in, entries on the way out
+    let mut len = capacity * core::mem::size_of::<KdBuf>();
+    sysctl(&[KERN_KDREADTR], buffer.as_mut_ptr().cast(), &mut len)?;
+
+    buffer.truncate(len);
+    Ok(())
+}
+
+/// Stop tracing and free the buffer
+pub(crate) fn stop() {
+    let mut unused = 0;
+    let _ = sysctl(&[KERN_KDENABLE, 0], core::ptr::null_mut(), &mut unused);
+    let _ = sysctl(&[KERN_KDREMOVE], core::ptr::null_mut(), &mut unused);
+}
@@ -0,0 +1,478 @@
+//! Reading the kpep event databases of macOS (`/usr/share/kpep/<cpu>.plist`) without the
+//! kperfdata framework, so that they can be inspected and checked on any OS.
+//!
+//! The files are XML or binary property lists. The parts that `kpep_db_create` uses look like
+//! this:
+//!
+//! ```text
+//! name: "a14", marketing_name: "Apple A14/M1", id: "cpu_100000c_2_1b588bb3"
+//! system:
+//!   cpu:
+//!     architecture: "arm64"
+//!     fixed_counters: 2, config_counters: 8, power_counters: 0
+//!     fixed_counter_bits: 48, config_counter_bits: 48, power_counter_bits: 48
+//!     aliases: { "Cycles": "FIXED_CYCLES", ... }
+//!     events:
+//!       FIXED_CYCLES: { description, fixed_counter: 0 }
+//!       INST_BRANCH: { description, number: 0x8d, counters_mask: 0xe0 }
+//!       BR_INST_RETIRED.ALL_BRANCHES: { description, number: 0xc4, umask: 0, fallback, errata }
+//! ```
+//!
+//! Missing keys are read as empty or zero, like the framework does. `fixtures/kpep` has a small
+//! database in each format: an XML one for the A14 and a binary one for Haswell.
+
+use std::collections::BTreeMap;
+#[cfg(target_os = "macos")]
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+use plist::{Dictionary, Value};
+
+use crate::aliases::{AliasTable, PROFILE_ALIASES};
+
+/// Where macOS keeps the event databases
+pub const DATABASE_DIR: &str = "/usr/share/kpep";
+
+/// The contents of one database, like `kpep_db`
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    /// Such as "haswell" or "a14"
+    pub name: String,
+    /// The name of the plist, such as "cpu_7_8_10b282dc"
+    pub cpu_id: String,
+    /// Such as "Intel Haswell"
+    pub marketing_name: String,
+    pub architecture: String,
+    /// Sorted by name
+    pub events: Vec<Event>,
+    /// From alias, such as "Cycles", to event name
+    pub aliases: BTreeMap<String, String>,
+    pub fixed_counter_count: usize,
+    pub config_counter_count: usize,
+    pub power_counter_count: usize,
+    pub fixed_counter_bits: u32,
+    pub config_counter_bits: u32,
+    pub power_counter_bits: u32,
+}
+
+/// One event of a database, like `kpep_event`
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    /// Such as "INST_RETIRED.ANY"
+    pub name: String,
+    pub description: String,
+    pub errata: Option<String>,
+    /// The first alias that refers to this event, such as "Instructions"
+    pub alias: Option<String>,
+    /// The configurable event to use instead of a fixed counter
+    pub fallback: Option<String>,
+    /// The counters that can count this event, one bit per counter
+    pub mask: u32,
+    pub number: u8,
+    pub umask: u8,
+    pub is_fixed: bool,
+}
+
+impl Database {
+    /// The path of the database with the plist name `cpu_id`
+    pub fn path(cpu_id: &str) -> PathBuf {
+        Path::new(DATABASE_DIR).join(format!("{cpu_id}.plist"))
+    }
+
+    /// Read a database from an XML or binary plist
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let value =
+            Value::from_file(path).map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+
+        let mut database = Self::from_value(&value)?;
+        if database.cpu_id.is_empty() {
+            if let Some(stem) = path.file_stem() {
+                database.cpu_id = stem.to_string_lossy().into_owned();
+            }
+        }
+
+        Ok(database)
+    }
+
+    pub fn from_value(value: &Value) -> std::io::Result<Self> {
+        let root = value
+            .as_dictionary()
+            .ok_or_else(|| invalid("the database is not a dictionary"))?;
+        let cpu = root
+            .get("system")
+            .and_then(Value::as_dictionary)
+            .and_then(|system| system.get("cpu"))
+            .and_then(Value::as_dictionary)
+            .ok_or_else(|| invalid("the database has no system.cpu dictionary"))?;
+
+        let mut aliases = BTreeMap::new();
+        if let Some(dictionary) = cpu.get("aliases").and_then(Value::as_dictionary) {
+            for (alias, name) in dictionary {
+                let name = name
+                    .as_string()
+                    .ok_or_else(|| invalid(format!("alias {alias} is not a string")))?;
+                aliases.insert(alias.clone(), name.to_owned());
+            }
+        }
+
+        let mut events = Vec::new();
+        if let Some(dictionary) = cpu.get("events").and_then(Value::as_dictionary) {
+            for (name, event) in dictionary {
+                let event = event
+                    .as_dictionary()
+                    .ok_or_else(|| invalid(format!("event {name} is not a dictionary")))?;
+                events.push(Event::from_dictionary(name, event, &aliases));
+            }
+        }
+        events.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self {
+            name: string(root, "name").unwrap_or_default(),
+            cpu_id: string(root, "id").unwrap_or_default(),
+            marketing_name: string(root, "marketing_name").unwrap_or_default(),
+            architecture: string(cpu, "architecture").unwrap_or_default(),
+            events,
+            aliases,
+            fixed_counter_count: counter_count(cpu, "fixed_counters"),
+            config_counter_count: counter_count(cpu, "config_counters"),
+            power_counter_count: counter_count(cpu, "power_counters"),
+            fixed_counter_bits: integer(cpu, "fixed_counter_bits") as u32,
+            config_counter_bits: integer(cpu, "config_counter_bits") as u32,
+            power_counter_bits: integer(cpu, "power_counter_bits") as u32,
+        })
+    }
+
+    /// The event with this name or alias, like `kpep_db_event`
+    pub fn event(&self, name: &str) -> Option<&Event> {
+        let by_name = |name: &str| {
+            self.events
+                .binary_search_by(|event| event.name.as_str().cmp(name))
+                .ok()
+                .map(|i| &self.events[i])
+        };
+
+        by_name(name).or_else(|| self.aliases.get(name).and_then(|name| by_name(name)))
+    }
+
+    /// The events of the fixed counters
+    pub fn fixed_events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter().filter(|event| event.is_fixed)
+    }
+
+    /// The event that counts `alias` of the alias table, like `EventCollector` would pick it
+    pub fn resolve_alias(&self, table: &AliasTable, alias: &str) -> Option<&Event> {
+        table
+            .names(alias)
+            .into_iter()
+            .find_map(|name| self.event(name))
+    }
+
+    /// The events that `EventCollector` would count with this database, in the order of
+    /// `PerformanceCounters`' columns, or `None` for the ones that it would not find
+    pub fn profile_events(&self) -> Vec<(&'static str, Option<&Event>)> {
+        let table = crate::aliases::table();
+        PROFILE_ALIASES
+            .iter()
+            .map(|alias| (*alias, self.resolve_alias(&table, alias)))
+            .collect()
+    }
+
+    /// Everything that is inconsistent in the database, such as aliases of missing events, or
+    /// events that no counter can count
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (alias, name) in self.aliases.iter() {
+            if self.event(name).is_none() {
+                problems.push(format!("alias {alias} refers to the missing event {name}"));
+            }
+        }
+
+        // some databases number the configurable counters after the fixed ones, others from 0
+        let fixed_mask = low_bits(self.fixed_counter_count);
+        let config_mask = low_bits(self.fixed_counter_count + self.config_counter_count);
+        for event in self.events.iter() {
+            let counters = if event.is_fixed {
+                fixed_mask
+            } else {
+                config_mask
+            };
+            if event.mask == 0 && event.is_fixed {
+                problems.push(format!(
+                    "event {} has a fixed counter beyond the 32 that a mask can hold",
+                    event.name
+                ));
+            } else if event.mask == 0 {
+                problems.push(format!("event {} has no counters", event.name));
+            } else if event.mask & !counters != 0 {
+                problems.push(format!(
+                    "event {} uses counters {:#x}, but there are only {counters:#x}",
+                    event.name, event.mask
+                ));
+            }
+
+            if let Some(fallback) = &event.fallback {
+                if self.event(fallback).is_none() {
+                    problems.push(format!(
+                        "event {} falls back to the missing event {fallback}",
+                        event.name
+                    ));
+                }
+            }
+        }
+
+        for (alias, event) in self.profile_events() {
+            if event.is_none() {
+                problems.push(format!("no event for {alias}"));
+            }
+        }
+
+        problems
+    }
+}
+
+impl Event {
+    fn from_dictionary(name: &str, event: &Dictionary, aliases: &BTreeMap<String, String>) -> Self {
+        let fixed_counter = event
+            .get("fixed_counter")
+            .and_then(Value::as_unsigned_integer);
+
+        // a fixed counter that does not fit into the mask leaves it empty, see `problems`
+        let mask = match fixed_counter {
+            Some(counter) => u32::try_from(counter)
+                .ok()
+                .and_then(|counter| 1u32.checked_shl(counter))
+                .unwrap_or(0),
+            None => integer(event, "counters_mask") as u32,
+        };
+
+        Self {
+            name: name.to_owned(),
+            description: string(event, "description").unwrap_or_default(),
+            errata: string(event, "errata"),
+            alias: aliases
+                .iter()
+                .find(|(_, event)| *event == name)
+                .map(|(alias, _)| alias.clone()),
+            fallback: string(event, "fallback"),
+            mask,
+            number: integer(event, "number") as u8,
+            umask: integer(event, "umask") as u8,
+            is_fixed: fixed_counter.is_some(),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+fn string(dictionary: &Dictionary, key: &str) -> Option<String> {
+    dictionary
+        .get(key)
+        .and_then(Value::as_string)
+        .map(str::to_owned)
+}
+
+fn integer(dictionary: &Dictionary, key: &str) -> u64 {
+    dictionary
+        .get(key)
+        .and_then(Value::as_unsigned_integer)
+        .unwrap_or_default()
+}
+
+/// The counters are given either as a count, or as a mask with one bit per counter
+fn counter_count(dictionary: &Dictionary, key: &str) -> usize {
+    match dictionary.get(key) {
+        Some(Value::Integer(count)) => count.as_unsigned().unwrap_or_default() as usize,
+        Some(Value::Array(counters)) => counters.len(),
+        _ => 0,
+    }
+}
+
+fn low_bits(count: usize) -> u32 {
+    1u32.checked_shl(count as u32)
+        .map_or(u32::MAX, |bit| bit - 1)
+}
+
+/// The `kpep` subcommand: `kpep [FILE.plist]`, which prints a database, by default the one of
+/// this CPU on macOS, and exits with 1 if it finds problems in it
+pub fn main(args: &[String]) -> ! {
+    let path = match args {
+        [path] => PathBuf::from(path),
+        [] => match current_database() {
+            Some(path) => path,
+            None => {
+                eprintln!("usage: kpep FILE.plist");
+                std::process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("usage: kpep [FILE.plist]");
+            std::process::exit(2);
+        }
+    };
+
+    let database = match Database::open(&path) {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "{} ({}), {}, {}",
+        database.name, database.marketing_name, database.cpu_id, database.architecture
+    );
+    println!(
+        "counters: {} fixed ({} bits), {} configurable ({} bits), {} power ({} bits)",
+        database.fixed_counter_count,
+        database.fixed_counter_bits,
+        database.config_counter_count,
+        database.config_counter_bits,
+        database.power_counter_count,
+        database.power_counter_bits
+    );
+    println!(
+        "{} events, {} aliases",
+        database.events.len(),
+        database.aliases.len()
+    );
+
+    println!();
+    for (alias, event) in database.profile_events() {
+        match event {
+            Some(event) => println!(
+                "{alias:>16}: {} (number {:#x}, umask {:#x}, counters {:#x}{})",
+                event.name,
+                event.number,
+                event.umask,
+                event.mask,
+                if event.is_fixed { ", fixed" } else { "" }
+            ),
+            None => println!("{alias:>16}: -"),
+        }
+    }
+
+    let problems = database.problems();
+    if problems.is_empty() {
+        std::process::exit(0);
+    }
+
+    eprintln!();
+    for problem in problems.iter() {
+        eprintln!("{problem}");
+    }
+    std::process::exit(1);
+}
+
+/// The database of this CPU, which is named after `hw.cputype`, `hw.cpusubtype` and
+/// `hw.cpufamily` like "cpu_7_8_10b282dc"
+fn current_database() -> Option<PathBuf> {
+    #[cfg(target_os = "macos")]
+    {
+        let sysctl = |name: &CStr| -> Option<u32> {
+            let mut value = 0u32;
+            let mut size = core::mem::size_of_val(&value);
+            let ret = unsafe {
+                libc::sysctlbyname(
+                    name.as_ptr(),
+                    (&mut value as *mut u32).cast(),
+                    &mut size,
+                    core::ptr::null_mut(),
+                    0,
+                )
+            };
+            (ret == 0).then_some(value)
+        };
+
+        let cpu_type = sysctl(c"hw.cputype")?;
+        let cpu_subtype = sysctl(c"hw.cpusubtype")?;
+        let cpu_family = sysctl(c"hw.cpufamily")?;
+
+        let path = Database::path(&format!("cpu_{cpu_type:x}_{cpu_subtype:x}_{cpu_family:x}"));
+        path.exists().then_some(path)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Database {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/kpep")
+            .join(name);
+        Database::open(path).unwrap()
+    }
+
+    #[test]
+    fn the_a14_xml_database_parses() {
+        let database = fixture("cpu_100000c_2_1b588bb3.plist");
+        assert_eq!(database.name, "a14");
+        assert_eq!(database.architecture, "arm64");
+        assert_eq!(
+            (database.fixed_counter_count, database.config_counter_count),
+            (2, 8)
+        );
+
+        let cycles = database.event("Cycles").unwrap();
+        assert_eq!(cycles.name, "FIXED_CYCLES");
+        assert!(cycles.is_fixed);
+        assert_eq!(cycles.mask, 1);
+
+        let branches = database.event("INST_BRANCH").unwrap();
+        assert_eq!((branches.number, branches.umask), (0x8d, 0));
+        assert_eq!(branches.mask, 0xe0);
+        assert!(!branches.is_fixed);
+
+        assert_eq!(database.problems(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn the_haswell_binary_database_parses() {
+        let database = fixture("cpu_7_8_10b282dc.plist");
+        assert_eq!(database.name, "haswell");
+        assert_eq!(
+            (database.fixed_counter_count, database.config_counter_count),
+            (3, 4)
+        );
+
+        let instructions = database.event("INST_RETIRED.ANY").unwrap();
+        assert!(instructions.is_fixed);
+        assert_eq!(instructions.mask, 1);
+        assert_eq!(instructions.fallback.as_deref(), Some("INST_RETIRED.ANY_P"));
+
+        let branches = database.event("BR_INST_RETIRED.ALL_BRANCHES").unwrap();
+        assert_eq!((branches.number, branches.umask), (0xc4, 0));
+        assert_eq!(branches.mask, 0xf);
+
+        let misses = database.event("LONGEST_LAT_CACHE.MISS").unwrap();
+        assert_eq!((misses.number, misses.umask), (0x2e, 0x41));
+
+        assert_eq!(database.problems(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_fixed_counter_beyond_the_mask_is_a_problem() {
+        let mut event = Dictionary::new();
+        event.insert("fixed_counter".into(), Value::Integer(40.into()));
+        let event = Event::from_dictionary("FIXED_FAR", &event, &BTreeMap::new());
+        assert_eq!(event.mask, 0);
+
+        let database = Database {
+            fixed_counter_count: 41,
+            events: vec![event],
+            ..Database::default()
+        };
+        assert!(database
+            .problems()
+            .iter()
+            .any(|problem| problem.contains("FIXED_FAR")));
+    }
+}
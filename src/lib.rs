@@ -0,0 +1,1522 @@
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use libloading::Library;
+use serde::{Deserialize, Serialize};
+
+use aliases::PROFILE_ALIASES;
+
+pub mod aliases;
+pub mod attach;
+pub mod baseline;
+pub mod export;
+mod function;
+mod future;
+pub mod harness;
+mod interval;
+#[cfg(not(target_os = "linux"))]
+mod kdebug;
+#[cfg(target_os = "linux")]
+mod linux;
+pub mod modes;
+pub mod multiplex;
+pub mod passes;
+pub mod pmu;
+pub mod raw;
+pub mod sampling;
+mod scope;
+pub mod session;
+pub mod stat;
+pub mod system;
+pub mod table;
+pub mod threads;
+pub mod timeseries;
+mod tree;
+
+#[cfg(feature = "criterion")]
+mod criterion_measurement;
+#[cfg(feature = "kpep")]
+pub mod kpep;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+
+pub use function::{function_report, print_function_report, Function, FunctionGuard};
+pub use future::{CountEvents, Counted};
+#[cfg(feature = "macros")]
+pub use performancecounters_macros::count_events;
+pub use scope::{print_report, report, Region, Scope};
+pub use tree::{call_tree, enter, CallNode, CallTree, Entered};
+
+#[cfg(feature = "criterion")]
+pub use criterion_measurement::CounterMeasurement;
+#[cfg(feature = "tracing")]
+pub use tracing_layer::CounterLayer;
+
+const LIB_PATH_KPERF: &str = "/System/Library/PrivateFrameworks/kperf.framework/kperf";
+const LIB_PATH_KPERFDATA: &str = "/System/Library/PrivateFrameworks/kperfdata.framework/kperfdata";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Run {
+    pub mean: PerformanceCounters,
+    pub minimum: PerformanceCounters,
+    pub maximum: PerformanceCounters,
+    pub standard_deviation: PerformanceCounters,
+}
+
+/// Run `f` `repeat` times, and return the statistics of the events of `PerformanceCounters`.
+///
+/// These are the four events that the collector sets up to count together, so unlike
+/// `passes::count_events`, which packs any number of raw events into passes with `passes::plan`,
+/// there is nothing to plan: every run counts all of them.
+pub fn count_events(repeat: usize, f: impl Fn()) -> Run {
+    let mut collector = EventCollector::load();
+    let samples = collect_samples(&mut collector, repeat, f);
+
+    Run::from_samples(&samples)
+}
+
+/// Run `f` `repeat` times, and return the events counted for every iteration
+pub fn collect_samples(
+    collector: &mut EventCollector,
+    repeat: usize,
+    f: impl Fn(),
+) -> Vec<EventCount> {
+    let mut samples = Vec::with_capacity(repeat);
+
+    for _ in 0..repeat {
+        collector.start();
+
+        f();
+
+        samples.push(collector.end());
+    }
+
+    samples
+}
+
+impl Run {
+    pub fn from_samples(samples: &[EventCount]) -> Self {
+        let repeat = samples.len().max(1);
+
+        let mut total = PerformanceCounters::default();
+        let mut minimum = PerformanceCounters::from_value(1e300);
+        let mut maximum = PerformanceCounters::from_value(0.0);
+
+        for sample in samples.iter() {
+            let sample = PerformanceCounters::from_event_count(*sample);
+            total += sample;
+            minimum.min(&sample);
+            maximum.max(&sample);
+        }
+
+        let mut mean = total;
+        mean /= repeat as f64;
+
+        let mut variance = PerformanceCounters::default();
+
+        for sample in samples.iter() {
+            let sample = PerformanceCounters::from_event_count(*sample);
+            let diff = sample - mean;
+            variance += diff.squared();
+        }
+
+        // the population standard deviation, like `Aggregate::run`
+        variance /= repeat as f64;
+
+        Run {
+            mean,
+            minimum,
+            maximum,
+            standard_deviation: variance.sqrt(),
+        }
+    }
+}
+
+/// Running statistics over samples that arrive one at a time, for when keeping every sample
+/// around (like `count_events` does) is not an option.
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregate {
+    count: usize,
+    elapsed: core::time::Duration,
+    total: PerformanceCounters,
+    total_squared: PerformanceCounters,
+    minimum: PerformanceCounters,
+    maximum: PerformanceCounters,
+}
+
+impl Default for Aggregate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Aggregate {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            elapsed: core::time::Duration::ZERO,
+            total: PerformanceCounters::from_value(0.0),
+            total_squared: PerformanceCounters::from_value(0.0),
+            minimum: PerformanceCounters::from_value(1e300),
+            maximum: PerformanceCounters::from_value(0.0),
+        }
+    }
+
+    pub fn push(&mut self, event_count: EventCount) {
+        let sample = PerformanceCounters::from_event_count(event_count);
+        self.push_counters(sample, event_count.elapsed);
+    }
+
+    pub fn push_counters(&mut self, sample: PerformanceCounters, elapsed: core::time::Duration) {
+        self.count += 1;
+        self.elapsed += elapsed;
+        self.total += sample;
+        self.total_squared += sample.squared();
+        self.minimum.min(&sample);
+        self.maximum.max(&sample);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn elapsed(&self) -> core::time::Duration {
+        self.elapsed
+    }
+
+    pub fn total(&self) -> PerformanceCounters {
+        self.total
+    }
+
+    pub fn run(&self) -> Run {
+        let n = self.count.max(1) as f64;
+
+        let mut mean = self.total;
+        mean /= n;
+
+        let mut mean_of_squares = self.total_squared;
+        mean_of_squares /= n;
+
+        // rounding can push the variance of (nearly) constant samples just below zero
+        let mut variance = mean_of_squares - mean.squared();
+        variance.max(&PerformanceCounters::from_value(0.0));
+
+        Run {
+            mean,
+            minimum: self.minimum,
+            maximum: self.maximum,
+            standard_deviation: variance.sqrt(),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(into = "export::Sample", from = "export::Sample")]
+pub struct EventCount {
+    pub elapsed: core::time::Duration,
+    pub event_counts: [u64; 5],
+}
+
+impl std::fmt::Debug for EventCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventCount")
+            .field("elapsed", &self.elapsed)
+            .field("event_counts", &self.event_counts)
+            .field("cycles", &self.cycles())
+            .field("instructions", &self.instructions())
+            .field("missed_branches", &self.missed_branches())
+            .field("branches", &self.branches())
+            .finish()
+    }
+}
+
+impl EventCount {
+    pub fn from_counters(counters: PerformanceCounters, elapsed: core::time::Duration) -> Self {
+        Self {
+            elapsed,
+            event_counts: [
+                counters.cycles as u64,
+                counters.instructions as u64,
+                counters.missed_branches as u64,
+                0,
+                counters.branches as u64,
+            ],
+        }
+    }
+
+    /// The counts of the deltas of `CounterValues`, without going through `f64`
+    pub fn from_values(values: CounterValues, elapsed: core::time::Duration) -> Self {
+        Self {
+            elapsed,
+            event_counts: [
+                values.cycles,
+                values.instructions,
+                values.missed_branches,
+                0,
+                values.branches,
+            ],
+        }
+    }
+
+    pub const fn cycles(self) -> u64 {
+        self.event_counts[0]
+    }
+
+    pub const fn instructions(self) -> u64 {
+        self.event_counts[1]
+    }
+
+    pub const fn missed_branches(self) -> u64 {
+        self.event_counts[2]
+    }
+
+    pub const fn branches(self) -> u64 {
+        self.event_counts[4]
+    }
+}
+
+/// Read the raw counters of the current thread with the global session
+pub(crate) fn read_thread_values() -> std::io::Result<CounterValues> {
+    session::read_current_thread_values()
+}
+
+/// The counters between two reads of `read_thread_values`, or `None` if either read failed or the
+/// counters went backwards. The first failure is reported on stderr, so that the callers can leave
+/// out the measurement instead of recording zeros.
+pub(crate) fn thread_delta(
+    start: &std::io::Result<CounterValues>,
+    end: &std::io::Result<CounterValues>,
+) -> Option<PerformanceCounters> {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    let delta = match (start, end) {
+        (Ok(start), Ok(end)) => end.delta(start),
+        (Err(e), _) | (_, Err(e)) => Err(std::io::Error::new(e.kind(), e.to_string())),
+    };
+    match delta {
+        Ok(values) => Some(values.to_counters()),
+        Err(e) => {
+            if !WARNED.fetch_or(true, std::sync::atomic::Ordering::Relaxed) {
+                eprintln!("Failed to count events: {e}");
+            }
+            None
+        }
+    }
+}
+
+/// The sum of the deltas of every CPU between two reads of `EventCollector::read_cpu_values`. The
+/// counters of every CPU wrap on their own, so the deltas are taken per CPU.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub(crate) fn cpus_delta(
+    start: &[CounterValues],
+    end: &[CounterValues],
+) -> std::io::Result<PerformanceCounters> {
+    let mut total = PerformanceCounters::default();
+    for (start, end) in start.iter().zip(end) {
+        total += end.delta(start)?.to_counters();
+    }
+    Ok(total)
+}
+
+/// Held while the PMU is reprogrammed, so that two collectors or sessions never interleave their
+/// configuration
+static PMU: Mutex<()> = Mutex::new(());
+
+pub(crate) fn lock_pmu() -> MutexGuard<'static, ()> {
+    PMU.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Bumped every time raw events are programmed into the PMU or the configuration from before them
+/// is put back, so that a session reader can tell that the counters changed since its last read
+#[cfg(not(target_os = "linux"))]
+static PMU_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// The number of `RawCounters` that have the PMU, while which the session counts other events
+#[cfg(not(target_os = "linux"))]
+static PMU_BORROWS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Record that the PMU was programmed with other events than the ones of the session; called
+/// while holding `lock_pmu`
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pmu_reprogrammed() {
+    PMU_GENERATION.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Record that raw events took the PMU (`taken`) or gave it back to the session
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pmu_borrowed(taken: bool) {
+    use std::sync::atomic::Ordering;
+
+    if taken {
+        PMU_BORROWS.fetch_add(1, Ordering::SeqCst);
+    } else {
+        PMU_BORROWS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The generation of the PMU configuration, or an error while raw events have the PMU
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pmu_generation() -> std::io::Result<u64> {
+    use std::sync::atomic::Ordering;
+
+    if PMU_BORROWS.load(Ordering::SeqCst) != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ResourceBusy,
+            "the counters are programmed with raw events",
+        ));
+    }
+    Ok(PMU_GENERATION.load(Ordering::SeqCst))
+}
+
+/// Counts the events of the thread that calls `start` and `end`.
+///
+/// A collector is `Send` and `Sync`, but it always counts the calling thread, so `start` and `end`
+/// have to be called on the same thread. Every collector programs the PMU when it is created; to
+/// share one configuration between threads, use `session::Session` instead.
+pub struct EventCollector {
+    count: EventCount,
+    start_clock: Instant,
+
+    // apple-specific
+    apple_events: AppleEvents,
+    /// The counters at `start`, or why they could not be read
+    start_values: std::io::Result<CounterValues>,
+
+    // kept around so that they can be dropped at the end
+    kperf: Option<&'static Library>,
+    kperfdata: Option<&'static Library>,
+
+    kperf_symbols: KperfSymbols<'static>,
+    kperfdata_symbols: KperfDataSymbols<'static>,
+}
+
+impl Drop for EventCollector {
+    fn drop(&mut self) {
+        if let Some(library) = self.kperf.take() {
+            let _ = unsafe { Box::from_raw(library as *const Library as *mut Library) };
+        }
+
+        if let Some(library) = self.kperfdata.take() {
+            let _ = unsafe { Box::from_raw(library as *const Library as *mut Library) };
+        }
+    }
+}
+
+impl EventCollector {
+    /// Load the kperf and kperfdata frameworks and set up the performance counters
+    pub fn load() -> Self {
+        let kperf = match unsafe { libloading::Library::new(LIB_PATH_KPERF) } {
+            Ok(lib) => lib,
+            Err(e) => {
+                panic!("Error loading {LIB_PATH_KPERF}: {:?}", e)
+            }
+        };
+
+        let kperfdata = match unsafe { libloading::Library::new(LIB_PATH_KPERFDATA) } {
+            Ok(lib) => lib,
+            Err(e) => {
+                panic!("Error loading {LIB_PATH_KPERFDATA}: {:?}", e)
+            }
+        };
+
+        Self::new(kperf, kperfdata)
+    }
+
+    pub fn new(kperf: Library, kperfdata: Library) -> Self {
+        let kperf = Box::leak(Box::new(kperf));
+        let kperf_symbols = unsafe { KperfSymbols::load(kperf).unwrap() };
+
+        let kperfdata = Box::leak(Box::new(kperfdata));
+        let kperfdata_symbols = unsafe { KperfDataSymbols::load(kperfdata).unwrap() };
+
+        let mut apple_events = AppleEvents::new();
+        apple_events.setup_performance_counters(&kperf_symbols, &kperfdata_symbols);
+
+        Self {
+            count: EventCount::default(),
+            start_clock: Instant::now(),
+            apple_events,
+            start_values: Ok(CounterValues::default()),
+
+            kperf: Some(kperf),
+            kperf_symbols,
+
+            kperfdata: Some(kperfdata),
+            kperfdata_symbols,
+        }
+    }
+
+    fn has_events(&mut self) -> bool {
+        self.apple_events
+            .setup_performance_counters(&self.kperf_symbols, &self.kperfdata_symbols)
+    }
+
+    #[inline(always)]
+    pub fn start(&mut self) {
+        self.start_clock = Instant::now();
+
+        if self.has_events() {
+            self.start_values = self.apple_events.get_values(&self.kperf_symbols);
+        }
+    }
+
+    /// The events since `start`, or no events if the counters could not be read or went backwards
+    #[inline(always)]
+    pub fn end(&mut self) -> EventCount {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+
+        match self.try_end() {
+            Ok(count) => count,
+            Err(e) => {
+                if !WARNED.fetch_or(true, std::sync::atomic::Ordering::Relaxed) {
+                    eprintln!("Failed to count events: {e}");
+                }
+                self.count
+            }
+        }
+    }
+
+    /// The events since `start`, or an error if the counters could not be read or went backwards
+    #[inline(always)]
+    pub fn try_end(&mut self) -> std::io::Result<EventCount> {
+        let elapsed = self.start_clock.elapsed();
+        self.count = EventCount::from_values(CounterValues::default(), elapsed);
+
+        if self.has_events() {
+            let start = match &self.start_values {
+                Ok(start) => *start,
+                Err(e) => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("the counters could not be read at the start: {e}"),
+                    ))
+                }
+            };
+            let end = self.apple_events.get_values(&self.kperf_symbols)?;
+            let values = end.delta(&start)?;
+            self.count = EventCount::from_values(values, elapsed);
+        }
+
+        Ok(self.count)
+    }
+
+    /// The name of the CPU according to the PMC database
+    pub fn cpu(&self) -> Option<&str> {
+        self.apple_events.cpu.as_deref()
+    }
+
+    /// The configurable counters that can count the event with `number` and `umask`, one bit per
+    /// counter, or `None` if the PMC database does not have the event
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub(crate) fn event_counters(&self, number: u16, umask: u8) -> Option<u32> {
+        self.apple_events
+            .masks
+            .iter()
+            .find(|(n, u, _)| *n as u16 == number && *u == umask)
+            .map(|(_, _, mask)| *mask)
+    }
+
+    /// The counters of the CPU according to the PMC database, if the counters could be set up
+    pub fn counter_layout(&self) -> Option<CounterLayout> {
+        self.apple_events.worked.then_some(self.apple_events.layout)
+    }
+
+    /// The counters of every CPU, counting everything that runs on them rather than just this thread
+    pub fn read_cpus(&mut self) -> std::io::Result<Vec<PerformanceCounters>> {
+        if self.has_events() {
+            self.apple_events.get_cpu_counters(&self.kperf_symbols)
+        } else {
+            Err(std::io::Error::other(
+                "performance counters are not available",
+            ))
+        }
+    }
+
+    /// The current value of the counters, for callers that keep track of deltas themselves
+    #[inline(always)]
+    pub fn read(&mut self) -> PerformanceCounters {
+        if self.has_events() {
+            self.apple_events.get_counters(&self.kperf_symbols)
+        } else {
+            PerformanceCounters::default()
+        }
+    }
+
+    /// The raw values of the counters, for exact deltas with `CounterValues::delta`; zeros when
+    /// there are no counters
+    #[inline(always)]
+    pub fn read_values(&mut self) -> std::io::Result<CounterValues> {
+        if self.has_events() {
+            self.apple_events.get_values(&self.kperf_symbols)
+        } else {
+            Ok(CounterValues::default())
+        }
+    }
+
+    /// The raw values of the counters of every CPU, like `read_cpus`
+    pub fn read_cpu_values(&mut self) -> std::io::Result<Vec<CounterValues>> {
+        if self.has_events() {
+            self.apple_events.get_cpu_values(&self.kperf_symbols)
+        } else {
+            Err(std::io::Error::other(
+                "performance counters are not available",
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PerformanceCounters {
+    pub cycles: f64,
+    pub branches: f64,
+    pub missed_branches: f64,
+    pub instructions: f64,
+}
+
+impl PerformanceCounters {
+    // Constructors
+    pub fn new_u64(c: u64, b: u64, m: u64, i: u64) -> Self {
+        Self {
+            cycles: c as f64,
+            branches: b as f64,
+            missed_branches: m as f64,
+            instructions: i as f64,
+        }
+    }
+
+    pub fn new_f64(c: f64, b: f64, m: f64, i: f64) -> Self {
+        Self {
+            cycles: c,
+            branches: b,
+            missed_branches: m,
+            instructions: i,
+        }
+    }
+
+    pub fn from_event_count(event_count: EventCount) -> Self {
+        Self {
+            cycles: event_count.cycles() as f64,
+            branches: event_count.branches() as f64,
+            missed_branches: event_count.missed_branches() as f64,
+            instructions: event_count.instructions() as f64,
+        }
+    }
+
+    pub const fn from_value(init: f64) -> Self {
+        Self {
+            cycles: init,
+            branches: init,
+            missed_branches: init,
+            instructions: init,
+        }
+    }
+
+    pub fn squared(self) -> Self {
+        Self {
+            cycles: self.cycles * self.cycles,
+            branches: self.branches * self.branches,
+            missed_branches: self.missed_branches * self.missed_branches,
+            instructions: self.instructions * self.instructions,
+        }
+    }
+
+    pub fn sqrt(self) -> Self {
+        Self {
+            cycles: self.cycles.sqrt(),
+            branches: self.branches.sqrt(),
+            missed_branches: self.missed_branches.sqrt(),
+            instructions: self.instructions.sqrt(),
+        }
+    }
+
+    // Methods for in-place operations
+    fn subtract_assign(&mut self, other: &Self) {
+        self.cycles -= other.cycles;
+        self.branches -= other.branches;
+        self.missed_branches -= other.missed_branches;
+        self.instructions -= other.instructions;
+    }
+
+    fn add_assign(&mut self, other: &Self) {
+        self.cycles += other.cycles;
+        self.branches += other.branches;
+        self.missed_branches += other.missed_branches;
+        self.instructions += other.instructions;
+    }
+
+    fn divide_assign(&mut self, numerator: f64) {
+        self.cycles /= numerator;
+        self.branches /= numerator;
+        self.missed_branches /= numerator;
+        self.instructions /= numerator;
+    }
+
+    pub fn min(&mut self, other: &Self) {
+        self.cycles = f64::min(self.cycles, other.cycles);
+        self.branches = f64::min(self.branches, other.branches);
+        self.missed_branches = f64::min(self.missed_branches, other.missed_branches);
+        self.instructions = f64::min(self.instructions, other.instructions);
+    }
+
+    pub fn max(&mut self, other: &Self) {
+        self.cycles = f64::max(self.cycles, other.cycles);
+        self.branches = f64::max(self.branches, other.branches);
+        self.missed_branches = f64::max(self.missed_branches, other.missed_branches);
+        self.instructions = f64::max(self.instructions, other.instructions);
+    }
+}
+
+/// Selects one of the fields of `PerformanceCounters`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    Cycles,
+    Instructions,
+    Branches,
+    MissedBranches,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 4] = [
+        Counter::Cycles,
+        Counter::Instructions,
+        Counter::Branches,
+        Counter::MissedBranches,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Counter::Cycles => "cycles",
+            Counter::Instructions => "instructions",
+            Counter::Branches => "branches",
+            Counter::MissedBranches => "missed_branches",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Counter::ALL
+            .into_iter()
+            .find(|counter| counter.name() == name)
+    }
+
+    pub const fn get(self, counters: &PerformanceCounters) -> f64 {
+        match self {
+            Counter::Cycles => counters.cycles,
+            Counter::Instructions => counters.instructions,
+            Counter::Branches => counters.branches,
+            Counter::MissedBranches => counters.missed_branches,
+        }
+    }
+}
+
+// Operator overloads as standalone functions
+impl std::ops::Sub for PerformanceCounters {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self::Output {
+        Self {
+            cycles: self.cycles - other.cycles,
+            branches: self.branches - other.branches,
+            missed_branches: self.missed_branches - other.missed_branches,
+            instructions: self.instructions - other.instructions,
+        }
+    }
+}
+
+impl std::ops::SubAssign for PerformanceCounters {
+    fn sub_assign(&mut self, other: Self) {
+        self.subtract_assign(&other);
+    }
+}
+
+impl std::ops::AddAssign for PerformanceCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.add_assign(&other);
+    }
+}
+
+impl std::ops::DivAssign<f64> for PerformanceCounters {
+    fn div_assign(&mut self, numerator: f64) {
+        self.divide_assign(numerator);
+    }
+}
+
+/// The maximum number of counters we could read from every class in one go.
+/// ARMV7: FIXED: 1, CONFIGURABLE: 4
+/// ARM32: FIXED: 2, CONFIGURABLE: 6
+/// ARM64: FIXED: 2, CONFIGURABLE: CORE_NCTRS - FIXED (6 or 8)
+/// x86: 32
+const KPC_MAX_COUNTERS: usize = 32;
+
+/// KPEP event (size: 48/28 bytes on 64/32 bit OS)
+#[repr(C)]
+#[allow(non_camel_case_types, dead_code)]
+struct kpep_event {
+    ///< Unique name of a event, such as "INST_RETIRED.ANY".
+    name: *const c_char,
+    ///< Description for this event.
+    description: *const c_char,
+    ///< Errata, currently NULL.
+    errata: *const c_char,
+    ///< Alias name, such as "Instructions", "Cycles".
+    alias: *const c_char,
+    ///< Fallback event name for fixed counter.
+    fallback: *const c_char,
+    mask: u32,
+    number: u8,
+    umask: u8,
+    reserved: u8,
+    is_fixed: u8,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types, dead_code)]
+struct kpep_config {
+    db: *mut kpep_db,
+    ///< (sizeof(kpep_event *) * counter_count), init NULL
+    ///< (sizeof(usize *) * counter_count), init 0
+    ev_map: *mut usize,
+    ///< (sizeof(usize *) * counter_count), init -1
+    ev_idx: *mut usize,
+    ///< (sizeof(u32 *) * counter_count), init 0
+    flags: *mut i32,
+    ///< (sizeof(u64 *) * counter_count), init 0
+    kpc_periods: *mut u64,
+    /// kpep_config_events_count()
+    event_count: usize,
+    counter_count: usize,
+    classes: u32,
+    ///< See `class mask constants` above.
+    config_counter: u32,
+    power_counter: u32,
+    reserved: u32,
+}
+
+/// The first of `names` that the database has, by name or by alias
+unsafe fn get_event(
+    kperfdata: &KperfDataSymbols,
+    db: *mut kpep_db,
+    names: &[&str],
+) -> *mut kpep_event {
+    for name in names {
+        let Ok(name) = CString::new(*name) else {
+            continue;
+        };
+
+        let mut ev = core::ptr::null_mut();
+        if (kperfdata.kpep_db_event)(db, name.as_ptr(), &mut ev) == 0 {
+            return ev;
+        }
+    }
+
+    core::ptr::null_mut()
+}
+
+/// How many counters of each class the CPU has, according to the PMC database
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CounterLayout {
+    /// Counters that always count the same event, such as cycles
+    pub fixed: usize,
+    /// Counters that count any event that they support
+    pub configurable: usize,
+    /// The widths of the counters of each class, in bits
+    pub fixed_bits: u32,
+    pub configurable_bits: u32,
+}
+
+/// The events that a counter of `bits` bits counted from `start` to `end`.
+///
+/// A value that went down wrapped around, if both values fit into the counter; the kernels extend
+/// most counters to 64 bits, and for those, or for values that do not fit, it is an error.
+pub fn counter_delta(start: u64, end: u64, bits: u32) -> std::io::Result<u64> {
+    if let Some(delta) = end.checked_sub(start) {
+        return Ok(delta);
+    }
+
+    if (1..64).contains(&bits) && (start | end) >> bits == 0 {
+        return Ok((1 << bits) - start + end);
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("a {bits}-bit counter went backwards from {start} to {end}"),
+    ))
+}
+
+/// The raw values of the counters of `PerformanceCounters`, which stay integers until the deltas
+/// are aggregated, and the widths of the counters that they were read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterValues {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub branches: u64,
+    pub missed_branches: u64,
+    /// The widths of the counters, in the order of the fields
+    pub bits: [u32; 4],
+}
+
+impl Default for CounterValues {
+    fn default() -> Self {
+        Self {
+            cycles: 0,
+            instructions: 0,
+            branches: 0,
+            missed_branches: 0,
+            bits: [64; 4],
+        }
+    }
+}
+
+impl CounterValues {
+    /// The events from `start` to `self`, see `counter_delta`
+    pub fn delta(&self, start: &CounterValues) -> std::io::Result<CounterValues> {
+        let [cycles, instructions, branches, missed_branches] = self.bits;
+        Ok(CounterValues {
+            cycles: counter_delta(start.cycles, self.cycles, cycles)?,
+            instructions: counter_delta(start.instructions, self.instructions, instructions)?,
+            branches: counter_delta(start.branches, self.branches, branches)?,
+            missed_branches: counter_delta(
+                start.missed_branches,
+                self.missed_branches,
+                missed_branches,
+            )?,
+            bits: self.bits,
+        })
+    }
+
+    pub fn to_counters(&self) -> PerformanceCounters {
+        PerformanceCounters::new_u64(
+            self.cycles,
+            self.branches,
+            self.missed_branches,
+            self.instructions,
+        )
+    }
+}
+
+struct AppleEvents {
+    regs: [u64; KPC_MAX_COUNTERS],
+    counter_map: [usize; KPC_MAX_COUNTERS],
+    counters_0: [u64; KPC_MAX_COUNTERS],
+    classes: u32,
+    cpu: Option<String>,
+    layout: CounterLayout,
+    /// The widths of the counters of the events, in the order of `CounterValues`
+    bits: [u32; 4],
+    /// The number, umask and configurable counters (one bit per counter) of the configurable
+    /// events of the database, to check raw events against
+    masks: Vec<(u8, u8, u32)>,
+    init: bool,
+    worked: bool,
+}
+
+impl AppleEvents {
+    fn new() -> Self {
+        Self {
+            regs: [0; KPC_MAX_COUNTERS],
+            counter_map: [0; KPC_MAX_COUNTERS],
+            counters_0: [0; KPC_MAX_COUNTERS],
+            classes: 0,
+            cpu: None,
+            layout: CounterLayout::default(),
+            bits: [64; 4],
+            masks: Vec::new(),
+            init: false,
+            worked: false,
+        }
+    }
+
+    fn setup_performance_counters(
+        &mut self,
+        kperf_symbols: &KperfSymbols,
+        kperfdata_symbols: &KperfDataSymbols,
+    ) -> bool {
+        if self.init {
+            return self.worked;
+        }
+        self.init = true;
+
+        let _pmu = lock_pmu();
+
+        // Check permission
+        let mut force_ctrs = 0;
+        if unsafe { (kperf_symbols.kpc_force_all_ctrs_get)(&mut force_ctrs) } != 0 {
+            eprintln!("Permission denied, xnu/kpc requires root privileges.");
+            self.worked = false;
+            return false;
+        }
+
+        // Load PMC database
+        let mut db: *mut kpep_db = core::ptr::null_mut();
+        match unsafe { (kperfdata_symbols.kpep_db_create)(core::ptr::null_mut(), &mut db) } {
+            0 => { /* all good */ }
+            ret => {
+                eprintln!("Error: cannot load pmc database: {}.", ret);
+                self.worked = false;
+                return false;
+            }
+        };
+
+        let name = unsafe { CStr::from_ptr((*db).name).to_string_lossy() };
+        let marketing_name = unsafe { CStr::from_ptr((*db).marketing_name).to_string_lossy() };
+        eprintln!("Loaded db: {} ({})", name, marketing_name);
+        self.cpu = Some(format!("{} ({})", name, marketing_name));
+        self.layout = unsafe {
+            CounterLayout {
+                fixed: (*db).fixed_counter_count,
+                configurable: (*db).config_counter_count,
+                fixed_bits: (*db).fixed_counter_bits,
+                configurable_bits: (*db).config_counter_bits,
+            }
+        };
+        self.masks = unsafe { event_masks(kperfdata_symbols, db) };
+
+        // create a config
+        let mut cfg: *mut kpep_config = core::ptr::null_mut();
+        match unsafe { (kperfdata_symbols.kpep_config_create)(db, &mut cfg) } {
+            0 => {}
+            ret => {
+                // eprintln!( "Failed to create kpep config: %d (%s).\n", ret, kpep_config_error_desc(ret),);
+                eprintln!("Failed to create kpep config: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+
+        match unsafe { (kperfdata_symbols.kpep_config_force_counters)(cfg) } {
+            0 => {}
+            ret => {
+                // printf( "Failed to force counters: %d (%s).\n", ret, kpep_config_error_desc(ret),);
+                eprintln!("Failed to force counters: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+
+        // get events
+        let aliases = aliases::table();
+        let mut ev_arr: [*mut kpep_event; PROFILE_ALIASES.len()] =
+            [core::ptr::null_mut(); PROFILE_ALIASES.len()];
+        for (i, alias) in PROFILE_ALIASES.iter().enumerate() {
+            ev_arr[i] = unsafe { get_event(kperfdata_symbols, db, &aliases.names(alias)) };
+            if ev_arr[i].is_null() {
+                // printf("Cannot find event: %s.\n", alias->alias);
+                eprintln!("Cannot find event: {alias}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+
+        // add event to config; the flag 1 counts user space only, for the fixed counters too
+        let modes = modes::modes();
+        let flag = (modes.user && !modes.kernel) as u32;
+
+        // kpep has no flag for the kernel only, and the fixed counters have no config register
+        let fixed = ev_arr.iter().find(|ev| unsafe { (***ev).is_fixed } != 0);
+        if let (Some(fixed), true) = (fixed, modes.kernel && !modes.user) {
+            let name = unsafe { CStr::from_ptr((**fixed).name).to_string_lossy() };
+            eprintln!("Cannot count {name} of a fixed counter in the kernel only ({modes})");
+            self.worked = false;
+            return self.worked;
+        }
+        for ev in ev_arr.iter_mut() {
+            match unsafe {
+                (kperfdata_symbols.kpep_config_add_event)(cfg, ev, flag, core::ptr::null_mut())
+            } {
+                0 => {}
+                ret => {
+                    // printf( "Failed to add event: %d (%s).\n", ret, kpep_config_error_desc(ret),);
+                    eprintln!("Failed to add event: {ret}");
+                    self.worked = false;
+                    return self.worked;
+                }
+            }
+        }
+
+        // prepare buffer and config
+        let mut classes: u32 = 0;
+        let mut reg_count: usize = 0;
+        match unsafe { (kperfdata_symbols.kpep_config_kpc_classes)(cfg, &mut classes) } {
+            0 => {}
+            ret => {
+                // printf("Failed get kpc classes: %d (%s).\n", ret, kpep_config_error_desc(ret));
+                eprintln!("Failed get kpc classes: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+        match unsafe { (kperfdata_symbols.kpep_config_kpc_count)(cfg, &mut reg_count) } {
+            0 => {}
+            ret => {
+                // printf("Failed get kpc count: %d (%s).\n", ret, kpep_config_error_desc(ret));
+                eprintln!("Failed get kpc count: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+        match unsafe {
+            (kperfdata_symbols.kpep_config_kpc_map)(
+                cfg,
+                self.counter_map.as_mut_ptr(),
+                core::mem::size_of_val(&self.counter_map),
+            )
+        } {
+            0 => {}
+            ret => {
+                // printf("Failed get kpc map: %d (%s).\n", ret, kpep_config_error_desc(ret));
+
+                eprintln!("Failed get kpc map: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+        match unsafe {
+            (kperfdata_symbols.kpep_config_kpc)(
+                cfg,
+                self.regs.as_mut_ptr(),
+                core::mem::size_of_val(&self.regs),
+            )
+        } {
+            0 => {}
+            ret => {
+                // printf("Failed get kpc registers: %d (%s).\n", ret, kpep_config_error_desc(ret));
+                eprintln!("Failed get kpc registers: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+
+        // kpep has no flag for the kernel only, so the modes go into the config registers of the
+        // configurable counters, which follow the ones of the fixed counters
+        let fixed_configs = if (classes & KPC_CLASS_FIXED_MASK as u32) != 0 {
+            unsafe { (kperf_symbols.kpc_get_config_count)(KPC_CLASS_FIXED_MASK as u32) as usize }
+        } else {
+            0
+        };
+        // the counters of the fixed class come first
+        let fixed_counters = if (classes & KPC_CLASS_FIXED_MASK as u32) != 0 {
+            unsafe { (kperf_symbols.kpc_get_counter_count)(KPC_CLASS_FIXED_MASK as u32) as usize }
+        } else {
+            0
+        };
+        let width = |bits: u32| if bits == 0 { 64 } else { bits };
+        for (i, bits) in self.bits.iter_mut().enumerate() {
+            *bits = if self.counter_map[i] < fixed_counters {
+                width(self.layout.fixed_bits)
+            } else {
+                width(self.layout.configurable_bits)
+            };
+        }
+
+        for register in self.regs[..reg_count.min(KPC_MAX_COUNTERS)]
+            .iter_mut()
+            .skip(fixed_configs)
+        {
+            *register = modes::kpc_config(*register, modes);
+        }
+
+        // set config to kernel
+        match unsafe { (kperf_symbols.kpc_force_all_ctrs_set)(1) } {
+            0 => {}
+            ret => {
+                eprintln!("Failed force all ctrs: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+        if (classes & KPC_CLASS_CONFIGURABLE_MASK as u32) != 0 && reg_count != 0 {
+            match unsafe { (kperf_symbols.kpc_set_config)(classes, self.regs.as_ptr()) } {
+                0 => {}
+                ret => {
+                    eprintln!("Failed set kpc config: {ret}");
+                    self.worked = false;
+                    return self.worked;
+                }
+            }
+        }
+
+        // start counting
+        self.classes = classes;
+        match unsafe { (kperf_symbols.kpc_set_counting)(classes) } {
+            0 => {}
+            ret => {
+                eprintln!("Failed set counting: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+        match unsafe { (kperf_symbols.kpc_set_thread_counting)(classes) } {
+            0 => {}
+            ret => {
+                eprintln!("Failed set thread counting: {ret}");
+                self.worked = false;
+                return self.worked;
+            }
+        }
+
+        self.worked = true;
+        self.worked
+    }
+
+    fn get_counters(&mut self, kperf: &KperfSymbols) -> PerformanceCounters {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        match self.get_values(kperf) {
+            Ok(values) => values.to_counters(),
+            Err(_) => {
+                if !WARNED.fetch_or(true, std::sync::atomic::Ordering::Relaxed) {
+                    eprintln!("Failed to get thread counters.");
+                }
+
+                PerformanceCounters::from_value(1.0)
+            }
+        }
+    }
+
+    /// The raw values of the counters of this thread
+    fn get_values(&mut self, kperf: &KperfSymbols) -> std::io::Result<CounterValues> {
+        match unsafe {
+            (kperf.kpc_get_thread_counters)(
+                0,
+                KPC_MAX_COUNTERS as u32,
+                self.counters_0.as_mut_ptr(),
+            )
+        } {
+            0 => Ok(self.values_from_raw(&self.counters_0)),
+            ret => Err(std::io::Error::other(format!(
+                "Failed to get thread counters: {ret}"
+            ))),
+        }
+    }
+
+    /// Pick our counters out of the raw kpc counters, which are in the order of the classes
+    fn values_from_raw(&self, raw: &[u64]) -> CounterValues {
+        let counter = |i: usize| raw.get(self.counter_map[i]).copied().unwrap_or_default();
+
+        CounterValues {
+            cycles: counter(0),
+            instructions: counter(1),
+            branches: counter(2),
+            missed_branches: counter(3),
+            bits: self.bits,
+        }
+    }
+
+    /// The counters of every CPU, which count everything that runs on that CPU
+    fn get_cpu_counters(
+        &mut self,
+        kperf: &KperfSymbols,
+    ) -> std::io::Result<Vec<PerformanceCounters>> {
+        let cpus = self.get_cpu_values(kperf)?;
+        Ok(cpus.iter().map(|values| values.to_counters()).collect())
+    }
+
+    /// The raw values of the counters of every CPU
+    fn get_cpu_values(&mut self, kperf: &KperfSymbols) -> std::io::Result<Vec<CounterValues>> {
+        let counter_count = unsafe { (kperf.kpc_get_counter_count)(self.classes) } as usize;
+        let cpu_count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as usize;
+
+        let mut buffer = vec![0u64; counter_count * cpu_count];
+        let mut current_cpu = 0;
+        match unsafe {
+            (kperf.kpc_get_cpu_counters)(true, self.classes, &mut current_cpu, buffer.as_mut_ptr())
+        } {
+            0 => {}
+            ret => {
+                let message = format!("Failed to get cpu counters: {ret}");
+                return Err(std::io::Error::other(message));
+            }
+        }
+
+        let cpus = buffer
+            .chunks_exact(counter_count.max(1))
+            .map(|counters| self.values_from_raw(counters))
+            .collect();
+
+        Ok(cpus)
+    }
+}
+
+/// The number, umask and configurable counters of every configurable event of `db`. The masks of
+/// arm64 databases count the fixed counters too, so they are shifted to start at the first
+/// configurable counter.
+unsafe fn event_masks(kperfdata: &KperfDataSymbols, db: *mut kpep_db) -> Vec<(u8, u8, u32)> {
+    let mut count = 0;
+    if (kperfdata.kpep_db_events_count)(db, &mut count) != 0 {
+        return Vec::new();
+    }
+
+    let mut events: Vec<*mut kpep_event> = vec![core::ptr::null_mut(); count];
+    if (kperfdata.kpep_db_events)(db, events.as_mut_ptr(), core::mem::size_of_val(&*events)) != 0 {
+        return Vec::new();
+    }
+
+    let shift = if (*db).archtecture == KPEP_ARCH_ARM64 {
+        (*db).fixed_counter_count as u32
+    } else {
+        0
+    };
+    events
+        .iter()
+        .filter(|event| !event.is_null() && (***event).is_fixed == 0)
+        .map(|event| {
+            let event = &**event;
+            (
+                event.number,
+                event.umask,
+                event.mask.checked_shr(shift).unwrap_or(0),
+            )
+        })
+        .collect()
+}
+
+// The architectures of `kpep_db`
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPEP_ARCH_ARM64: u32 = 3;
+
+/// KPEP database (size: 144/80 bytes on 64/32 bit OS)
+#[derive(Debug)]
+#[repr(C)]
+#[allow(non_camel_case_types, dead_code)]
+struct kpep_db {
+    ///< Database name, such as "haswell".
+    name: *const c_char,
+    ///< Plist name, such as "cpu_7_8_10b282dc".
+    cpu_id: *const c_char,
+    ///< Marketing name, such as "Intel Haswell".
+    marketing_name: *const c_char,
+    ///< Plist data (CFDataRef), currently NULL.
+    plist_data: *mut c_void,
+    ///< All events (CFDict<CFSTR(event_name), kpep_event *>).
+    event_map: *mut c_void,
+    ///< Event struct buffer (sizeof(kpep_event) * events_count).
+    event_arr: *mut kpep_event,
+    ///< Fixed counter events (sizeof(kpep_event *)
+    fixed_event_arr: *mut *mut kpep_event,
+
+    ///< All aliases (CFDict<CFSTR(event_name), kpep_event *>).             ///< * fixed_counter_count)
+    alias_map: *mut c_void,
+    reserved_1: usize,
+    reserved_2: usize,
+    reserved_3: usize,
+    ///< All events count
+    event_count: usize,
+    alias_count: usize,
+    fixed_counter_count: usize,
+    config_counter_count: usize,
+    power_counter_count: usize,
+    ///< see `KPEP CPU archtecture constants` above
+    archtecture: u32,
+    fixed_counter_bits: u32,
+    config_counter_bits: u32,
+    power_counter_bits: u32,
+}
+
+macro_rules! load_dynlib_symbols {
+    ( $struct_name:ident ; $( $field_name:ident : fn( $( $arg:ty ),* ) -> $ret:ty ),* $(,)? ) => {
+        #[allow(dead_code, clippy::unused_unit)]
+        pub struct $struct_name<'a> {
+            $( $field_name: libloading::Symbol<'a, unsafe extern "C" fn( $( $arg ),* ) -> $ret>, )*
+        }
+
+        impl<'a> $struct_name<'a> {
+            /// # Safety
+            ///
+            /// The symbols must have the signatures given here.
+            #[allow(clippy::unused_unit)]
+            pub unsafe fn load(lib: &'a libloading::Library) -> Result<Self, Box<dyn std::error::Error>> {
+                Ok($struct_name {
+                    $( $field_name: lib.get::<unsafe extern "C" fn( $( $arg ),* ) -> $ret>(stringify!($field_name).as_bytes())?, )*
+                })
+            }
+        }
+    };
+}
+
+load_dynlib_symbols!(
+    KperfSymbols;
+    kpc_pmu_version: fn() -> u32,
+    kpc_cpu_string: fn(*mut char, usize) -> i32,
+    kpc_set_counting: fn(u32) -> i32,
+    kpc_get_counting: fn() -> u32,
+    kpc_set_thread_counting: fn(u32) -> i32,
+    kpc_get_thread_counting: fn() -> u32,
+    kpc_get_config_count: fn(u32) -> u32,
+    kpc_get_counter_count: fn(u32) -> u32,
+    kpc_set_config: fn(u32, *const u64) -> i32,
+    kpc_get_config: fn(u32, *mut u64) -> i32,
+    kpc_get_cpu_counters: fn(bool, u32, *mut i32, *mut u64) -> i32,
+    kpc_get_thread_counters: fn(u32, u32, *mut u64) -> i32,
+    kpc_force_all_ctrs_set: fn(i32) -> i32,
+    kpc_force_all_ctrs_get: fn(*mut i32) -> i32,
+    kperf_action_count_set: fn(u32) -> i32,
+    kperf_action_count_get: fn(*mut u32) -> i32,
+    kperf_action_samplers_set: fn(u32, u32) -> i32,
+    kperf_action_samplers_get: fn(u32, *mut u32) -> i32,
+    kperf_action_filter_set_by_task: fn(u32, i32) -> i32,
+    kperf_action_filter_set_by_pid: fn(u32, i32) -> i32,
+    kperf_timer_count_set: fn(u32) -> i32,
+    kperf_timer_count_get: fn(*mut u32) -> i32,
+    kperf_timer_period_set: fn(u32, u64) -> i32,
+    kperf_timer_period_get: fn(u32, *mut u64) -> i32,
+    kperf_timer_action_set: fn(u32, u32) -> i32,
+    kperf_timer_action_get: fn(u32, *mut u32) -> i32,
+    kperf_sample_set: fn(u32) -> i32,
+    kperf_sample_get: fn(*mut u32) -> i32,
+    kperf_reset: fn() -> i32,
+    kperf_timer_pet_set: fn(u32) -> i32,
+    kperf_timer_pet_get: fn(*mut u32) -> i32,
+    kperf_ns_to_ticks: fn(u64) -> u64,
+    kperf_ticks_to_ns: fn(u64) -> u64,
+    kperf_tick_frequency: fn() -> u64,
+);
+
+load_dynlib_symbols!(
+    KperfDataSymbols;
+    kpep_config_create: fn(*mut kpep_db, *mut *mut kpep_config) -> i32,
+    kpep_config_free: fn(*mut kpep_config) -> (),
+    kpep_config_add_event: fn(*mut kpep_config, *mut *mut kpep_event, u32, *mut u32) -> i32,
+    kpep_config_remove_event: fn(*mut kpep_config, usize) -> i32,
+    kpep_config_force_counters: fn(*mut kpep_config) -> i32,
+    kpep_config_events_count: fn(*mut kpep_config, *mut usize) -> i32,
+    kpep_config_events: fn(*mut kpep_config, *mut *mut kpep_event, usize) -> i32,
+    kpep_config_kpc: fn(*mut kpep_config, *mut u64, usize) -> i32,
+    kpep_config_kpc_count: fn(*mut kpep_config, *mut usize) -> i32,
+    kpep_config_kpc_classes: fn(*mut kpep_config, *mut u32) -> i32,
+    kpep_config_kpc_map: fn(*mut kpep_config, *mut usize, usize) -> i32,
+    kpep_db_create: fn(*const c_char, *mut *mut kpep_db) -> i32,
+    kpep_db_free: fn(*mut kpep_db) -> (),
+    kpep_db_name: fn(*mut kpep_db, *mut *const c_char) -> i32,
+    kpep_db_aliases_count: fn(*mut kpep_db, *mut usize) -> i32,
+    kpep_db_aliases: fn(*mut kpep_db, *mut *const c_char, usize) -> i32,
+    kpep_db_counters_count: fn(*mut kpep_db, u8, *mut usize) -> i32,
+    kpep_db_events_count: fn(*mut kpep_db, *mut usize) -> i32,
+    kpep_db_events: fn(*mut kpep_db, *mut *mut kpep_event, usize) -> i32,
+    kpep_db_event: fn(*mut kpep_db, *const c_char, *mut *mut kpep_event) -> i32,
+    kpep_event_name: fn(*mut kpep_event, *mut *const c_char) -> i32,
+    kpep_event_alias: fn(*mut kpep_event, *mut *const c_char) -> i32,
+    kpep_event_description: fn(*mut kpep_event, *mut *const c_char) -> i32,
+);
+
+// -----------------------------------------------------------------------------
+// <kperf.framework> header (reverse engineered)
+// This framework wraps some sysctl calls to communicate with the kpc in kernel.
+// Most functions requires root privileges, or process is "blessed".
+// -----------------------------------------------------------------------------
+
+// Cross-platform class constants.
+#[allow(dead_code)]
+const KPC_CLASS_FIXED: usize = 0;
+const KPC_CLASS_CONFIGURABLE: usize = 1;
+#[allow(dead_code)]
+const KPC_CLASS_POWER: usize = 2;
+#[allow(dead_code)]
+const KPC_CLASS_RAWPMU: usize = 3;
+
+// Cross-platform class mask constants.
+#[allow(dead_code)]
+const KPC_CLASS_FIXED_MASK: usize = 1 << KPC_CLASS_FIXED; // 1
+const KPC_CLASS_CONFIGURABLE_MASK: usize = 1 << KPC_CLASS_CONFIGURABLE; // 2
+#[allow(dead_code)]
+const KPC_CLASS_POWER_MASK: usize = 1 << KPC_CLASS_POWER; // 4
+#[allow(dead_code)]
+const KPC_CLASS_RAWPMU_MASK: usize = 1 << KPC_CLASS_RAWPMU; // 8
+
+// Bits of the config words of configurable counters, `kpc_set_config` (see xnu
+// osfmk/x86_64/kpc_x86.c and osfmk/arm64/kpc.c).
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_X86_USR: u64 = 1 << 16;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_X86_OS: u64 = 1 << 17;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_X86_EN: u64 = 1 << 22;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_ARM64_EL0A32EN: u64 = 0x10000;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_ARM64_EL0A64EN: u64 = 0x20000;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPC_ARM64_EL1EN: u64 = 0x40000;
+
+// -----------------------------------------------------------------------------
+// kperf sampling (reverse engineered, see xnu osfmk/kperf/action.h)
+// -----------------------------------------------------------------------------
+
+// Samplers of an action, `kperf_action_samplers_set`.
+#[allow(dead_code)]
+const KPERF_SAMPLER_TH_INFO: u32 = 1 << 0;
+#[allow(dead_code)]
+const KPERF_SAMPLER_KSTACK: u32 = 1 << 2;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPERF_SAMPLER_USTACK: u32 = 1 << 3;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPERF_SAMPLER_PMC_THREAD: u32 = 1 << 4;
+
+// Number of timers and actions that kperf supports.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPERF_TIMER_MAX: u32 = 8;
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+const KPERF_ACTION_MAX: u32 = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(instructions: u64) -> EventCount {
+        EventCount::from_counters(
+            PerformanceCounters::new_u64(0, 0, 0, instructions),
+            core::time::Duration::ZERO,
+        )
+    }
+
+    #[test]
+    fn samples_and_aggregates_have_the_same_deviation() {
+        let samples: Vec<_> = [2, 4, 4, 4, 5, 5, 7, 9].into_iter().map(sample).collect();
+
+        let mut aggregate = Aggregate::new();
+        for sample in samples.iter() {
+            aggregate.push(*sample);
+        }
+
+        let run = Run::from_samples(&samples);
+        assert_eq!(run.mean.instructions, 5.0);
+        assert_eq!(run.standard_deviation.instructions, 2.0);
+        assert_eq!(aggregate.run().standard_deviation.instructions, 2.0);
+    }
+
+    #[test]
+    fn a_narrow_counter_wraps_around() {
+        let max = (1 << 48) - 1;
+        assert_eq!(counter_delta(max - 9, 5, 48).unwrap(), 15);
+        assert_eq!(counter_delta(5, 5, 48).unwrap(), 0);
+        assert_eq!(counter_delta(max, 0, 48).unwrap(), 1);
+    }
+
+    #[test]
+    fn a_full_width_counter_cannot_go_backwards() {
+        assert!(counter_delta(10, 5, 64).is_err());
+        assert_eq!(counter_delta(5, 10, 64).unwrap(), 5);
+    }
+
+    #[test]
+    fn values_wider_than_the_counter_are_an_error() {
+        // a counter that went backwards can only have wrapped if both values fit in its width
+        assert!(counter_delta(1 << 48, 5, 48).is_err());
+        assert!(counter_delta(10, 5, 0).is_err());
+        assert!(counter_delta(u64::MAX, 5, 63).is_err());
+    }
+
+    #[test]
+    fn large_deltas_keep_every_bit() {
+        let start = 3;
+        let end = (1 << 60) + 7;
+        assert_eq!(counter_delta(start, end, 64).unwrap(), (1 << 60) + 4);
+
+        let values = |cycles| CounterValues {
+            cycles,
+            ..CounterValues::default()
+        };
+        let delta = values(end).delta(&values(start)).unwrap();
+        assert_eq!(delta.cycles, (1 << 60) + 4);
+        assert!(values(start).delta(&values(end)).is_err());
+    }
+}
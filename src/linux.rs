@@ -0,0 +1,603 @@
+//! Counting with the `perf_event_open` system call, the Linux counterpart of kpc.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::{CounterValues, PerformanceCounters};
+
+// Event types
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+// Software events, for `PERF_TYPE_SOFTWARE`
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+
+// Generalized hardware events, for `PERF_TYPE_HARDWARE`
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+// Bits of the bitfield that follows `read_format`
+const ATTR_FLAG_DISABLED: u64 = 1 << 0;
+const ATTR_FLAG_INHERIT: u64 = 1 << 1;
+const ATTR_FLAG_EXCLUDE_USER: u64 = 1 << 4;
+const ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+const ATTR_FLAG_EXCLUDE_IDLE: u64 = 1 << 7;
+const ATTR_FLAG_INHERIT_STAT: u64 = 1 << 11;
+const ATTR_FLAG_ENABLE_ON_EXEC: u64 = 1 << 12;
+const ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL: u64 = 1 << 21;
+
+// Fields of the samples, for `sample_type`
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_READ: u64 = 1 << 4;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+
+// Layout of `read`, for `read_format`
+pub(crate) const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+pub(crate) const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+pub(crate) const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+// Types of the records in the ring buffer
+const PERF_RECORD_READ: u32 = 8;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+// Call chain entries at and above this mark the context of the following entries, `PERF_CONTEXT_*`
+const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+// Flags for `perf_event_open`
+const PERF_FLAG_FD_CLOEXEC: u64 = 1 << 3;
+
+// ioctls, `_IO('$', n)`
+const PERF_EVENT_IOC_ENABLE: u64 = 0x2400;
+
+/// `struct perf_event_attr` from `<linux/perf_event.h>` (size: 136 bytes, `PERF_ATTR_SIZE_VER8`)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    /// union with `sample_freq`
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    /// `disabled`, `inherit`, `pinned`, ... see the `ATTR_FLAG_*` constants
+    pub flags: u64,
+    /// union with `wakeup_watermark`
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    /// union with `bp_addr`, `kprobe_func` and `uprobe_path`
+    pub config1: u64,
+    /// union with `bp_len`, `kprobe_addr` and `probe_offset`
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub reserved_2: u16,
+    pub aux_sample_size: u32,
+    pub reserved_3: u32,
+    pub sig_data: u64,
+    pub config3: u64,
+}
+
+impl PerfEventAttr {
+    pub fn new(type_: u32, config: u64) -> Self {
+        Self {
+            type_,
+            size: core::mem::size_of::<Self>() as u32,
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// The attributes of an event from the event database
+    pub fn from_raw(event: &crate::pmu::RawEvent) -> Self {
+        Self {
+            config1: event.config1,
+            config2: event.config2,
+            ..Self::new(event.type_, event.config)
+        }
+    }
+
+    /// Count only in `modes`
+    pub fn set_modes(&mut self, modes: crate::modes::Modes) {
+        self.set_flag(ATTR_FLAG_EXCLUDE_USER, !modes.user);
+        self.set_flag(ATTR_FLAG_EXCLUDE_KERNEL, !modes.kernel);
+        self.set_flag(ATTR_FLAG_EXCLUDE_HV, !modes.hypervisor);
+        self.set_flag(ATTR_FLAG_EXCLUDE_IDLE, !modes.idle);
+    }
+
+    /// The hardware events of `PerformanceCounters`, in the modes of `modes::set_modes`
+    fn hardware(config: u64) -> Self {
+        let mut attr = Self::new(PERF_TYPE_HARDWARE, config);
+        attr.set_modes(crate::modes::modes());
+        attr
+    }
+
+    fn set_flag(&mut self, flag: u64, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+}
+
+/// Which process(es) and CPU(s) to count, and how
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Target {
+    /// 0 for the calling thread, -1 for all processes (requires `cpu` to be set)
+    pub pid: i32,
+    /// -1 for any CPU
+    pub cpu: i32,
+    /// Also count the threads and processes that the target creates after the events are opened
+    pub inherit: bool,
+    /// Start counting when the target calls `exec`, rather than right away
+    pub enable_on_exec: bool,
+}
+
+pub(crate) struct PerfEvent {
+    fd: OwnedFd,
+}
+
+impl PerfEvent {
+    pub fn open(attr: PerfEventAttr, target: Target) -> std::io::Result<Self> {
+        Self::open_in_group(attr, target, None)
+    }
+
+    /// Open an event that is scheduled together with `leader`, and that is enabled and disabled
+    /// with it. Without a leader the event starts disabled.
+    pub fn open_in_group(
+        mut attr: PerfEventAttr,
+        target: Target,
+        leader: Option<&PerfEvent>,
+    ) -> std::io::Result<Self> {
+        attr.set_flag(ATTR_FLAG_DISABLED, leader.is_none());
+        attr.set_flag(ATTR_FLAG_INHERIT, target.inherit);
+        attr.set_flag(ATTR_FLAG_ENABLE_ON_EXEC, target.enable_on_exec);
+
+        let group_fd = leader.map_or(-1, |leader| leader.fd.as_raw_fd());
+        let flags = PERF_FLAG_FD_CLOEXEC;
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                target.pid,
+                target.cpu,
+                group_fd,
+                flags,
+            )
+        };
+
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+        })
+    }
+
+    pub fn enable(&self) -> std::io::Result<()> {
+        match unsafe { libc::ioctl(self.fd.as_raw_fd(), PERF_EVENT_IOC_ENABLE as _, 0) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read(&self) -> std::io::Result<u64> {
+        let mut buffer = [0u64; 1];
+        self.read_into(&mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    /// Read the words that `read_format` asks for, such as the times and the values of a group
+    pub fn read_into(&self, buffer: &mut [u64]) -> std::io::Result<()> {
+        let n = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                core::mem::size_of_val(buffer),
+            )
+        };
+
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        if n as usize != core::mem::size_of_val(buffer) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "short read from perf event",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The ids of the threads of the process `pid`
+pub(crate) fn threads(pid: i32) -> std::io::Result<Vec<i32>> {
+    let mut threads = Vec::new();
+
+    for entry in std::fs::read_dir(format!("/proc/{pid}/task"))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            threads.push(tid);
+        }
+    }
+
+    Ok(threads)
+}
+
+/// The events of `PerformanceCounters`, opened for one target
+pub(crate) struct PerfCounters {
+    cycles: PerfEvent,
+    instructions: PerfEvent,
+    branches: PerfEvent,
+    missed_branches: PerfEvent,
+}
+
+impl PerfCounters {
+    pub fn open(target: Target) -> std::io::Result<Self> {
+        let open = |config| PerfEvent::open(PerfEventAttr::hardware(config), target);
+
+        Ok(Self {
+            cycles: open(PERF_COUNT_HW_CPU_CYCLES)?,
+            instructions: open(PERF_COUNT_HW_INSTRUCTIONS)?,
+            branches: open(PERF_COUNT_HW_BRANCH_INSTRUCTIONS)?,
+            missed_branches: open(PERF_COUNT_HW_BRANCH_MISSES)?,
+        })
+    }
+
+    pub fn enable(&self) -> std::io::Result<()> {
+        self.cycles.enable()?;
+        self.instructions.enable()?;
+        self.branches.enable()?;
+        self.missed_branches.enable()
+    }
+
+    pub fn read(&self) -> std::io::Result<PerformanceCounters> {
+        Ok(self.read_values()?.to_counters())
+    }
+
+    pub fn read_values(&self) -> std::io::Result<CounterValues> {
+        Ok(CounterValues {
+            cycles: self.cycles.read()?,
+            instructions: self.instructions.read()?,
+            branches: self.branches.read()?,
+            missed_branches: self.missed_branches.read()?,
+            ..CounterValues::default()
+        })
+    }
+}
+
+/// The memory mapped ring buffer into which the kernel writes the samples of an event
+pub(crate) struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    data_size: usize,
+}
+
+impl RingBuffer {
+    // offsets of `data_head` and `data_tail` in `struct perf_event_mmap_page`
+    const DATA_HEAD: usize = 1024;
+    const DATA_TAIL: usize = 1032;
+
+    /// Map a ring buffer of `pages` pages, which must be a power of two
+    pub fn map(event: &PerfEvent, pages: usize) -> std::io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // the first page holds the header
+        let len = (pages + 1) * page_size;
+
+        let base = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                event.fd.as_raw_fd(),
+                0,
+            )
+        };
+
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            base: base.cast(),
+            len,
+            data_size: pages * page_size,
+        })
+    }
+
+    /// Call `f` with the type and the body of every record that the kernel wrote since the last
+    /// call, and hand the space back to the kernel
+    pub fn drain(&mut self, mut f: impl FnMut(u32, &[u8])) {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let page_size = self.len - self.data_size;
+        let (head, tail) = unsafe {
+            (
+                &*self.base.add(Self::DATA_HEAD).cast::<AtomicU64>(),
+                &*self.base.add(Self::DATA_TAIL).cast::<AtomicU64>(),
+            )
+        };
+        let data = unsafe { core::slice::from_raw_parts(self.base.add(page_size), self.data_size) };
+
+        // records can wrap around the end of the buffer, so copy them out
+        let copy = |offset: u64, out: &mut [u8]| {
+            for (i, byte) in out.iter_mut().enumerate() {
+                *byte = data[(offset as usize + i) % data.len()];
+            }
+        };
+
+        let end = head.load(Ordering::Acquire);
+        let mut position = tail.load(Ordering::Relaxed);
+        let mut record = Vec::new();
+
+        while position < end {
+            // struct perf_event_header
+            let mut header = [0u8; 8];
+            copy(position, &mut header);
+            let type_ = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+            let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as usize;
+            if size < header.len() {
+                break;
+            }
+
+            record.resize(size - header.len(), 0);
+            copy(position + header.len() as u64, &mut record);
+            f(type_, &record);
+
+            position += size as u64;
+        }
+
+        tail.store(position, Ordering::Release);
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.cast(), self.len) };
+    }
+}
+
+/// A sample of the counters of one thread, taken by `SampledThread`
+pub(crate) struct RawSample {
+    pub tid: u32,
+    /// In nanoseconds, from the clock of perf
+    pub time: u64,
+    /// The values of the counters since they were enabled
+    pub counters: CounterValues,
+    /// The user space return addresses, innermost first
+    pub stack: Vec<u64>,
+}
+
+/// A timer that samples the counters of one thread: a CPU clock event that leads a group of the
+/// events of `PerformanceCounters`, and that writes the values of the whole group to its ring
+/// buffer every period
+pub(crate) struct SampledThread {
+    clock: PerfEvent,
+    _counters: [PerfEvent; 4],
+    ring: RingBuffer,
+    call_stacks: bool,
+}
+
+impl SampledThread {
+    pub fn open(tid: i32, period: std::time::Duration, call_stacks: bool) -> std::io::Result<Self> {
+        let target = Target {
+            pid: tid,
+            cpu: -1,
+            inherit: false,
+            enable_on_exec: false,
+        };
+
+        let mut attr = PerfEventAttr::new(PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK);
+        // the CPU clock counts nanoseconds
+        attr.sample_period = (period.as_nanos() as u64).max(1);
+        attr.sample_type = PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_READ;
+        attr.read_format = PERF_FORMAT_GROUP;
+        if call_stacks {
+            attr.sample_type |= PERF_SAMPLE_CALLCHAIN;
+            attr.set_flag(ATTR_FLAG_EXCLUDE_CALLCHAIN_KERNEL, true);
+        }
+
+        let clock = PerfEvent::open(attr, target)?;
+        let member = |config| {
+            PerfEvent::open_in_group(PerfEventAttr::hardware(config), target, Some(&clock))
+        };
+
+        // the order of the values in the samples
+        let counters = [
+            member(PERF_COUNT_HW_CPU_CYCLES)?,
+            member(PERF_COUNT_HW_INSTRUCTIONS)?,
+            member(PERF_COUNT_HW_BRANCH_INSTRUCTIONS)?,
+            member(PERF_COUNT_HW_BRANCH_MISSES)?,
+        ];
+
+        let ring = RingBuffer::map(&clock, 64)?;
+
+        Ok(Self {
+            clock,
+            _counters: counters,
+            ring,
+            call_stacks,
+        })
+    }
+
+    pub fn enable(&self) -> std::io::Result<()> {
+        self.clock.enable()
+    }
+
+    /// Call `f` with every sample taken since the last call
+    pub fn drain(&mut self, mut f: impl FnMut(RawSample)) {
+        let call_stacks = self.call_stacks;
+
+        self.ring.drain(|type_, record| {
+            if type_ != PERF_RECORD_SAMPLE {
+                return;
+            }
+
+            let mut words = record
+                .chunks_exact(8)
+                .map(|word| u64::from_ne_bytes(word.try_into().unwrap()));
+            let mut next = || words.next().unwrap_or_default();
+
+            // PERF_SAMPLE_TID: u32 pid, u32 tid
+            let tid = (next() >> 32) as u32;
+            let time = next();
+
+            // PERF_SAMPLE_READ with PERF_FORMAT_GROUP: nr, then the values of the clock and the
+            // counters
+            let nr = next();
+            let values: Vec<u64> = (0..nr).map(|_| next()).collect();
+            let value = |i: usize| values.get(i).copied().unwrap_or_default();
+            let counters = CounterValues {
+                cycles: value(1),
+                instructions: value(2),
+                branches: value(3),
+                missed_branches: value(4),
+                ..CounterValues::default()
+            };
+
+            let mut stack = Vec::new();
+            if call_stacks {
+                let nr = next();
+                stack.extend((0..nr).map(|_| next()).filter(|&ip| ip < PERF_CONTEXT_MAX));
+            }
+
+            f(RawSample {
+                tid,
+                time,
+                counters,
+                stack,
+            });
+        });
+    }
+}
+
+/// The events of `PerformanceCounters` for the calling thread and every thread that it creates
+/// afterwards. Reading them gives the sum of all of these threads, and every thread that exits
+/// writes its own counts to the ring buffers.
+///
+/// The kernel only maps ring buffers of inherited events that are bound to a CPU, so there is a
+/// set of events for every CPU.
+pub(crate) struct InheritedCounters {
+    /// Cycles, instructions, branches and missed branches, for every CPU
+    cpus: Vec<[(PerfEvent, RingBuffer); 4]>,
+}
+
+impl InheritedCounters {
+    pub fn open() -> std::io::Result<Self> {
+        let cpu_count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as i32;
+
+        let mut cpus = Vec::new();
+        for cpu in 0..cpu_count {
+            let target = Target {
+                pid: 0,
+                cpu,
+                inherit: true,
+                enable_on_exec: false,
+            };
+
+            let open = |config| -> std::io::Result<(PerfEvent, RingBuffer)> {
+                let mut attr = PerfEventAttr::hardware(config);
+                attr.set_flag(ATTR_FLAG_INHERIT_STAT, true);
+
+                let event = PerfEvent::open(attr, target)?;
+                let ring = RingBuffer::map(&event, 8)?;
+                Ok((event, ring))
+            };
+
+            let events = (|| -> std::io::Result<[(PerfEvent, RingBuffer); 4]> {
+                Ok([
+                    open(PERF_COUNT_HW_CPU_CYCLES)?,
+                    open(PERF_COUNT_HW_INSTRUCTIONS)?,
+                    open(PERF_COUNT_HW_BRANCH_INSTRUCTIONS)?,
+                    open(PERF_COUNT_HW_BRANCH_MISSES)?,
+                ])
+            })();
+
+            match events {
+                Ok(events) => cpus.push(events),
+                // the CPU is offline
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self { cpus })
+    }
+
+    pub fn enable(&self) -> std::io::Result<()> {
+        for events in self.cpus.iter() {
+            for (event, _) in events.iter() {
+                event.enable()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The sum of the calling thread and of all threads that it created, running or exited
+    pub fn read(&self) -> std::io::Result<PerformanceCounters> {
+        let mut values = [0u64; 4];
+        for events in self.cpus.iter() {
+            for ((event, _), value) in events.iter().zip(values.iter_mut()) {
+                *value += event.read()?;
+            }
+        }
+
+        let [cycles, instructions, branches, missed_branches] = values;
+        Ok(PerformanceCounters::new_u64(
+            cycles,
+            branches,
+            missed_branches,
+            instructions,
+        ))
+    }
+
+    /// The thread id and the counts of every thread that exited since the last call, in the order
+    /// in which they exited
+    pub fn exited(&mut self) -> Vec<(u32, PerformanceCounters)> {
+        let mut threads: Vec<(u32, [u64; 4])> = Vec::new();
+
+        for events in self.cpus.iter_mut() {
+            for (i, (_, ring)) in events.iter_mut().enumerate() {
+                ring.drain(|type_, record| {
+                    // u32 pid, u32 tid, u64 value
+                    if type_ != PERF_RECORD_READ || record.len() < 16 {
+                        return;
+                    }
+                    let tid = u32::from_ne_bytes(record[4..8].try_into().unwrap());
+                    let value = u64::from_ne_bytes(record[8..16].try_into().unwrap());
+
+                    match threads.iter_mut().find(|(thread, _)| *thread == tid) {
+                        Some((_, values)) => values[i] += value,
+                        None => {
+                            let mut values = [0; 4];
+                            values[i] = value;
+                            threads.push((tid, values));
+                        }
+                    }
+                });
+            }
+        }
+
+        threads
+            .into_iter()
+            .map(|(tid, [cycles, instructions, branches, missed_branches])| {
+                let counters =
+                    PerformanceCounters::new_u64(cycles, branches, missed_branches, instructions);
+                (tid, counters)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,153 @@
+//! The privilege levels in which events are counted: user space, the kernel, and on Linux also the
+//! hypervisor and the idle task.
+//!
+//! Raw events carry their own `Modes`, see `raw::Encoding`. The events of `PerformanceCounters`
+//! use the modes of `set_modes`, which have to be set before the counters are set up: on macOS
+//! that is when the first `EventCollector` or the session is created.
+//!
+//! Modes are written like the modifiers of `perf`: `u` for user space, `k` for the kernel and `h`
+//! for the hypervisor; naming any of them leaves out the others. `I` leaves out the idle task.
+//!
+//! On macOS the fixed counters can only leave out the kernel, through kpep, and the configurable
+//! ones honour `user` and `kernel`. The events of `PerformanceCounters` that are on fixed counters
+//! cannot count the kernel only, so such modes make the setup fail. A thread is never counted
+//! while the CPU is idle, and there is no hypervisor on macOS, so the other modes make no
+//! difference.
+
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Whether events are counted while the CPU runs in each mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Modes {
+    pub user: bool,
+    pub kernel: bool,
+    pub hypervisor: bool,
+    pub idle: bool,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl Modes {
+    pub const ALL: Self = Self {
+        user: true,
+        kernel: true,
+        hypervisor: true,
+        idle: true,
+    };
+
+    pub const USER: Self = Self {
+        user: true,
+        kernel: false,
+        hypervisor: false,
+        idle: true,
+    };
+
+    pub const KERNEL: Self = Self {
+        user: false,
+        kernel: true,
+        hypervisor: false,
+        idle: true,
+    };
+
+    /// Check that the events count in some mode
+    pub fn validate(&self) -> std::io::Result<()> {
+        if !self.user && !self.kernel && !self.hypervisor {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the events would not count in any mode",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Modes {
+    type Err = std::io::Error;
+
+    /// Parse `perf` modifiers such as `u`, `k`, `uk` or `kI`
+    fn from_str(modifiers: &str) -> Result<Self, Self::Err> {
+        let mut named = Self {
+            user: false,
+            kernel: false,
+            hypervisor: false,
+            idle: true,
+        };
+
+        for modifier in modifiers.chars() {
+            match modifier {
+                'u' => named.user = true,
+                'k' => named.kernel = true,
+                'h' => named.hypervisor = true,
+                'I' => named.idle = false,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{modifiers}: unknown modifier {modifier}, expected u, k, h or I"),
+                    ))
+                }
+            }
+        }
+
+        // only `I`, or nothing: every privilege level
+        if !named.user && !named.kernel && !named.hypervisor {
+            return Ok(Self {
+                idle: named.idle,
+                ..Self::ALL
+            });
+        }
+
+        Ok(named)
+    }
+}
+
+impl std::fmt::Display for Modes {
+    /// The modifiers, which are empty for `Modes::ALL`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !(self.user && self.kernel && self.hypervisor) {
+            for (mode, modifier) in [(self.user, 'u'), (self.kernel, 'k'), (self.hypervisor, 'h')] {
+                if mode {
+                    write!(f, "{modifier}")?;
+                }
+            }
+        }
+        if !self.idle {
+            write!(f, "I")?;
+        }
+        Ok(())
+    }
+}
+
+static MODES: RwLock<Modes> = RwLock::new(Modes::ALL);
+
+/// The modes of the events of `PerformanceCounters`
+pub fn modes() -> Modes {
+    *MODES.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Set the modes of the events of `PerformanceCounters`, for the counters that are set up from
+/// now on
+pub fn set_modes(modes: Modes) -> std::io::Result<()> {
+    modes.validate()?;
+    *MODES.write().unwrap_or_else(|e| e.into_inner()) = modes;
+    Ok(())
+}
+
+/// Replace the privilege bits of the config word of a configurable counter for `kpc_set_config`
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub(crate) fn kpc_config(config: u64, modes: Modes) -> u64 {
+    if cfg!(target_arch = "aarch64") {
+        let el0 = crate::KPC_ARM64_EL0A32EN | crate::KPC_ARM64_EL0A64EN;
+        let el1 = crate::KPC_ARM64_EL1EN;
+        config & !(el0 | el1)
+            | if modes.user { el0 } else { 0 }
+            | if modes.kernel { el1 } else { 0 }
+    } else {
+        let (usr, os) = (crate::KPC_X86_USR, crate::KPC_X86_OS);
+        config & !(usr | os) | if modes.user { usr } else { 0 } | if modes.kernel { os } else { 0 }
+    }
+}
@@ -0,0 +1,392 @@
+//! Counting more events than the CPU has counters, by taking turns.
+//!
+//! The events are split into groups that fit into the configurable counters, and only one group
+//! counts at a time. Every event knows how long it was enabled and how long it actually counted,
+//! and its count is scaled up by the ratio. The ratio is also the confidence of the estimate: an
+//! event that counted during a tenth of the time only saw a tenth of the workload, which is fine
+//! for steady loops and wrong for phases that it missed.
+//!
+//! On Linux the kernel rotates the groups on every scheduler tick. kpc has no rotation, so on
+//! macOS the groups take turns whenever the counting thread calls `rotate`; a thread that never
+//! calls it only counts the first group, and `count` fails for more than one group.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::raw::Encoding;
+
+/// A count that may only cover part of the time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scaled {
+    /// What the counter counted while it ran
+    pub raw: u64,
+    /// How long the event was enabled
+    pub enabled: Duration,
+    /// How long it was in a counter
+    pub running: Duration,
+}
+
+impl Scaled {
+    /// The estimated count for the whole time, or 0 if the event never ran
+    pub fn estimate(&self) -> f64 {
+        if self.running.is_zero() {
+            return 0.0;
+        }
+        self.raw as f64 * self.enabled.as_secs_f64() / self.running.as_secs_f64()
+    }
+
+    /// The part of the time that the event counted, from 0 to 1; 1 means that it was not
+    /// multiplexed and the count is exact
+    pub fn confidence(&self) -> f64 {
+        if self.enabled.is_zero() {
+            return 1.0;
+        }
+        (self.running.as_secs_f64() / self.enabled.as_secs_f64()).min(1.0)
+    }
+
+    /// The count between `start` and `self`, or an error if the count went backwards
+    pub fn since(&self, start: &Scaled) -> std::io::Result<Scaled> {
+        Ok(Scaled {
+            raw: crate::counter_delta(start.raw, self.raw, 64)?,
+            enabled: self.enabled.saturating_sub(start.enabled),
+            running: self.running.saturating_sub(start.running),
+        })
+    }
+}
+
+/// The number of events that can count at the same time, which is the size of the groups.
+///
+/// This is the number of general purpose counters of the core PMU, if sysfs has it (see
+/// `pmu::Pmu::counters`). Otherwise it is the number that every CPU of the architecture has: 4 on
+/// x86 with hyper-threading, and 6 on Arm. The NMI watchdog takes one of them on x86 while it is
+/// enabled. A group that does not fit never runs, and its events have a confidence of 0.
+#[cfg(target_os = "linux")]
+pub fn capacity() -> std::io::Result<usize> {
+    use crate::pmu::{EventDatabase, Roots};
+
+    let database = EventDatabase::load(&Roots::default())?;
+    let counters = database.core_pmu().and_then(|pmu| pmu.counters());
+
+    // without the file there is no watchdog
+    let watchdog = std::fs::read_to_string("/proc/sys/kernel/nmi_watchdog")
+        .is_ok_and(|enabled| enabled.trim() != "0");
+    Ok(linux_capacity(counters, watchdog))
+}
+
+/// The size of the groups for the counters from sysfs, if any, and whether the NMI watchdog runs
+#[cfg(target_os = "linux")]
+fn linux_capacity(counters: Option<usize>, watchdog: bool) -> usize {
+    if cfg!(target_arch = "aarch64") {
+        return counters.unwrap_or(6);
+    }
+
+    counters.unwrap_or(4).saturating_sub(watchdog as usize)
+}
+
+/// The number of events that can count at the same time, which is the size of the groups: the
+/// number of configurable counters
+#[cfg(not(target_os = "linux"))]
+pub fn capacity() -> std::io::Result<usize> {
+    let collector = crate::session::Session::global()?.collector();
+    let kperf = &collector.kperf_symbols;
+    let configurable = crate::KPC_CLASS_CONFIGURABLE_MASK as u32;
+
+    let _pmu = crate::lock_pmu();
+    let (counters, configs) = unsafe {
+        (
+            (kperf.kpc_get_counter_count)(configurable) as usize,
+            (kperf.kpc_get_config_count)(configurable) as usize,
+        )
+    };
+
+    Ok(counters.min(configs))
+}
+
+/// Split `count` events into groups of at most `capacity`, in order
+pub fn groups(count: usize, capacity: usize) -> Vec<std::ops::Range<usize>> {
+    let capacity = capacity.max(1);
+    (0..count)
+        .step_by(capacity)
+        .map(|start| start..(start + capacity).min(count))
+        .collect()
+}
+
+/// Counters of the calling thread for any number of raw events. Like `RawCounters`, they count
+/// the thread that opened them, so they cannot be sent to other threads.
+pub struct MultiplexedCounters {
+    #[cfg(target_os = "linux")]
+    groups: Vec<Vec<crate::linux::PerfEvent>>,
+
+    #[cfg(not(target_os = "linux"))]
+    events: Vec<Encoding>,
+    #[cfg(not(target_os = "linux"))]
+    groups: Vec<std::ops::Range<usize>>,
+    /// The group that is in the counters, and its counters
+    #[cfg(not(target_os = "linux"))]
+    current: usize,
+    #[cfg(not(target_os = "linux"))]
+    active: crate::raw::RawCounters,
+    /// When the current group was put into the counters, and what they had then
+    #[cfg(not(target_os = "linux"))]
+    turn: (std::time::Instant, Vec<u64>),
+    #[cfg(not(target_os = "linux"))]
+    opened: std::time::Instant,
+    /// The counts and running times of the turns that are over
+    #[cfg(not(target_os = "linux"))]
+    finished: Vec<Scaled>,
+
+    _thread: PhantomData<*const ()>,
+}
+
+impl MultiplexedCounters {
+    /// Open counters for `events`, in groups of `capacity()`
+    pub fn open(events: &[Encoding]) -> std::io::Result<Self> {
+        Self::with_capacity(events, capacity()?)
+    }
+
+    /// Open counters for `events`, in groups of `capacity`. A smaller capacity leaves counters to
+    /// other users, such as the NMI watchdog of Linux, which takes one on x86.
+    #[cfg(target_os = "linux")]
+    pub fn with_capacity(events: &[Encoding], capacity: usize) -> std::io::Result<Self> {
+        use crate::linux::{
+            PerfEvent, PerfEventAttr, Target, PERF_FORMAT_GROUP, PERF_FORMAT_TOTAL_TIME_ENABLED,
+            PERF_FORMAT_TOTAL_TIME_RUNNING,
+        };
+        use crate::pmu::{EventDatabase, Roots};
+
+        let database = EventDatabase::load(&Roots::default())?;
+        let target = Target {
+            pid: 0,
+            cpu: -1,
+            inherit: false,
+            enable_on_exec: false,
+        };
+
+        let mut opened = Vec::new();
+        for range in groups(events.len(), capacity) {
+            let mut group: Vec<PerfEvent> = Vec::with_capacity(range.len());
+            for encoding in &events[range] {
+                encoding.validate()?;
+                let mut attr = PerfEventAttr::from_raw(&database.encode_core(&encoding.terms())?);
+                attr.set_modes(encoding.modes);
+                attr.read_format = PERF_FORMAT_GROUP
+                    | PERF_FORMAT_TOTAL_TIME_ENABLED
+                    | PERF_FORMAT_TOTAL_TIME_RUNNING;
+                group.push(PerfEvent::open_in_group(attr, target, group.first())?);
+            }
+            opened.push(group);
+        }
+
+        for group in &opened {
+            group[0].enable()?;
+        }
+
+        Ok(Self {
+            groups: opened,
+            _thread: PhantomData,
+        })
+    }
+
+    /// Open counters for `events`, in groups of `capacity`
+    #[cfg(not(target_os = "linux"))]
+    pub fn with_capacity(events: &[Encoding], capacity: usize) -> std::io::Result<Self> {
+        for encoding in events {
+            encoding.validate()?;
+        }
+
+        let groups = groups(events.len(), capacity);
+        let first = groups.first().cloned().unwrap_or(0..0);
+        let mut active = crate::raw::RawCounters::open(&events[first])?;
+        let start = active.read()?;
+        let now = std::time::Instant::now();
+
+        Ok(Self {
+            events: events.to_vec(),
+            groups,
+            current: 0,
+            active,
+            turn: (now, start),
+            opened: now,
+            finished: vec![Scaled::default(); events.len()],
+            _thread: PhantomData,
+        })
+    }
+
+    /// The number of groups that take turns
+    pub fn group_count(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Put the next group into the counters. The kernel does this on Linux, where this does
+    /// nothing.
+    pub fn rotate(&mut self) -> std::io::Result<()> {
+        #[cfg(not(target_os = "linux"))]
+        if self.groups.len() > 1 {
+            self.finish_turn()?;
+            self.current = (self.current + 1) % self.groups.len();
+            let slots: Vec<_> = self.events[self.groups[self.current].clone()]
+                .iter()
+                .copied()
+                .map(Some)
+                .collect();
+            self.active.reprogram(&slots)?;
+            self.turn = (std::time::Instant::now(), self.active.read()?);
+        }
+
+        Ok(())
+    }
+
+    /// Add what the current group counted in its turn so far to `finished`, and start the turn
+    /// again
+    #[cfg(not(target_os = "linux"))]
+    fn finish_turn(&mut self) -> std::io::Result<()> {
+        let now = std::time::Instant::now();
+        let values = self.active.read()?;
+
+        let range = self.groups[self.current].clone();
+        for ((scaled, value), start) in self.finished[range]
+            .iter_mut()
+            .zip(&values)
+            .zip(&self.turn.1)
+        {
+            scaled.raw += crate::counter_delta(*start, *value, self.active.bits())?;
+            scaled.running += now - self.turn.0;
+        }
+
+        self.turn = (now, values);
+        Ok(())
+    }
+
+    /// The current counts of the events, in the order in which they were given
+    pub fn read(&mut self) -> std::io::Result<Vec<Scaled>> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut scaled = Vec::new();
+            for group in &self.groups {
+                // nr, time_enabled, time_running, and a value per event
+                let mut buffer = vec![0u64; 3 + group.len()];
+                group[0].read_into(&mut buffer)?;
+
+                let enabled = Duration::from_nanos(buffer[1]);
+                let running = Duration::from_nanos(buffer[2]);
+                scaled.extend(buffer[3..].iter().map(|raw| Scaled {
+                    raw: *raw,
+                    enabled,
+                    running,
+                }));
+            }
+            Ok(scaled)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.finish_turn()?;
+            let enabled = self.opened.elapsed();
+            Ok(self
+                .finished
+                .iter()
+                .map(|scaled| Scaled { enabled, ..*scaled })
+                .collect())
+        }
+    }
+
+    /// Run `f`, and return how often every event happened while it ran. On macOS the groups
+    /// cannot take turns while `f` runs, so this fails for more than one group; use `read` and
+    /// `rotate` between the iterations of a loop instead.
+    pub fn count<R>(&mut self, f: impl FnOnce() -> R) -> std::io::Result<(R, Vec<Scaled>)> {
+        #[cfg(not(target_os = "linux"))]
+        if self.groups.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "{} groups cannot take turns while counting a closure; use rotate",
+                    self.groups.len()
+                ),
+            ));
+        }
+
+        let start = self.read()?;
+        let value = f();
+        let end = self.read()?;
+
+        let counts = end
+            .iter()
+            .zip(start.iter())
+            .map(|(end, start)| end.since(start))
+            .collect::<std::io::Result<_>>()?;
+
+        Ok((value, counts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_fill_up_in_order() {
+        assert_eq!(groups(7, 3), [0..3, 3..6, 6..7]);
+        assert_eq!(groups(6, 3), [0..3, 3..6]);
+        assert_eq!(groups(2, 4), vec![0..2]);
+        assert!(groups(0, 4).is_empty());
+        // without counters, every event is a group of its own
+        assert_eq!(groups(2, 0), [0..1, 1..2]);
+    }
+
+    fn scaled(raw: u64, enabled_ms: u64, running_ms: u64) -> Scaled {
+        Scaled {
+            raw,
+            enabled: Duration::from_millis(enabled_ms),
+            running: Duration::from_millis(running_ms),
+        }
+    }
+
+    #[test]
+    fn an_event_that_always_ran_is_exact() {
+        let count = scaled(1000, 20, 20);
+        assert_eq!(count.estimate(), 1000.0);
+        assert_eq!(count.confidence(), 1.0);
+    }
+
+    #[test]
+    fn an_event_that_never_ran_has_no_estimate() {
+        let count = scaled(0, 20, 0);
+        assert_eq!(count.estimate(), 0.0);
+        assert_eq!(count.confidence(), 0.0);
+
+        // nor does an event that was never enabled, but nothing was missed either
+        let count = scaled(0, 0, 0);
+        assert_eq!(count.estimate(), 0.0);
+        assert_eq!(count.confidence(), 1.0);
+    }
+
+    #[test]
+    fn a_multiplexed_event_is_scaled_up() {
+        let count = scaled(1000, 40, 10);
+        assert_eq!(count.estimate(), 4000.0);
+        assert_eq!(count.confidence(), 0.25);
+    }
+
+    #[test]
+    fn counts_since_a_start() {
+        let count = scaled(1500, 40, 20).since(&scaled(500, 20, 10)).unwrap();
+        assert_eq!(count, scaled(1000, 20, 10));
+        assert!(scaled(500, 20, 10).since(&scaled(1500, 40, 20)).is_err());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn the_watchdog_takes_a_counter_on_x86() {
+        assert_eq!(linux_capacity(None, false), 4);
+        assert_eq!(linux_capacity(None, true), 3);
+        assert_eq!(linux_capacity(Some(6), false), 6);
+        assert_eq!(linux_capacity(Some(6), true), 5);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    fn arm_has_six_counters_unless_sysfs_tells() {
+        assert_eq!(linux_capacity(None, true), 6);
+        assert_eq!(linux_capacity(Some(20), false), 20);
+    }
+}
@@ -0,0 +1,371 @@
+//! Counting more events than the CPU has counters, by running the workload once per group.
+//!
+//! For a deterministic workload this is more precise than multiplexing: every event counts the
+//! whole run, only not the same run as the events of the other groups. The events are packed into
+//! as few passes as the counters allow. An event for a fixed counter takes that counter, and an
+//! event for the configurable counters takes one of the counters that its mask allows; some
+//! events of Apple CPUs can only be counted by some of the counters.
+//!
+//! The report labels every count with its pass, and has the time of every pass, which shows how
+//! much the runs differ.
+
+use std::time::{Duration, Instant};
+
+use crate::raw::Encoding;
+use crate::CounterLayout;
+
+/// An event to count in one of the passes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassEvent {
+    pub name: String,
+    pub encoding: Encoding,
+    /// The configurable counters that can count the event, one bit per counter
+    pub counters: u64,
+    /// The fixed counter that counts the event, if any. Only macOS reads fixed counters; elsewhere
+    /// the event takes a configurable counter.
+    pub fixed: Option<usize>,
+}
+
+impl PassEvent {
+    /// An event that any configurable counter can count, named after its encoding
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            name: encoding.to_string(),
+            encoding,
+            counters: u64::MAX,
+            fixed: None,
+        }
+    }
+
+    pub fn named(name: impl Into<String>, encoding: Encoding) -> Self {
+        Self {
+            name: name.into(),
+            ..Self::new(encoding)
+        }
+    }
+
+    /// An event of a kpep database, with the counters that the database allows for it
+    #[cfg(feature = "kpep")]
+    pub fn from_kpep(database: &crate::kpep::Database, event: &crate::kpep::Event) -> Self {
+        let encoding = Encoding {
+            event: event.number as u16,
+            umask: event.umask,
+            ..Encoding::default()
+        };
+
+        let mut pass_event = Self::named(&event.name, encoding);
+        if event.is_fixed {
+            pass_event.fixed = Some(event.mask.trailing_zeros() as usize);
+        } else if database.architecture == "arm64" {
+            // the masks of Apple CPUs count the fixed counters too
+            pass_event.counters = (event.mask >> database.fixed_counter_count) as u64;
+        } else {
+            pass_event.counters = event.mask as u64;
+        }
+        pass_event
+    }
+}
+
+/// Where an event counts in its pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    Fixed(usize),
+    Configurable(usize),
+}
+
+/// The counters of this CPU: on macOS the ones of the kpep database. Linux does not tell, so there
+/// are no fixed counters and as many configurable ones as `multiplex::capacity`, which the kernel
+/// extends to 64 bits.
+pub fn layout() -> std::io::Result<CounterLayout> {
+    #[cfg(target_os = "linux")]
+    {
+        Ok(CounterLayout {
+            fixed: 0,
+            configurable: crate::multiplex::capacity()?,
+            fixed_bits: 64,
+            configurable_bits: 64,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        crate::session::Session::global()?
+            .collector()
+            .counter_layout()
+            .ok_or_else(|| std::io::Error::other("performance counters are not available"))
+    }
+}
+
+/// Pack `events` into passes, as a list of (event index, counter) per pass. There is always at
+/// least one pass, so that the workload runs even without events.
+pub fn plan(
+    events: &[PassEvent],
+    layout: &CounterLayout,
+) -> std::io::Result<Vec<Vec<(usize, Counter)>>> {
+    // the counters that every pass has taken
+    let mut taken: Vec<Vec<Counter>> = vec![Vec::new()];
+    let mut passes: Vec<Vec<(usize, Counter)>> = vec![Vec::new()];
+
+    for (index, event) in events.iter().enumerate() {
+        let candidates: Vec<Counter> = match event.fixed {
+            Some(fixed) if fixed < layout.fixed => vec![Counter::Fixed(fixed)],
+            _ => (0..layout.configurable.min(64))
+                .filter(|counter| event.counters & 1 << counter != 0)
+                .map(Counter::Configurable)
+                .collect(),
+        };
+        if candidates.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("none of the counters can count {}", event.name),
+            ));
+        }
+
+        let free = taken.iter().enumerate().find_map(|(pass, taken)| {
+            let counter = candidates.iter().find(|counter| !taken.contains(counter))?;
+            Some((pass, *counter))
+        });
+        let (pass, counter) = free.unwrap_or_else(|| {
+            taken.push(Vec::new());
+            passes.push(Vec::new());
+            (passes.len() - 1, candidates[0])
+        });
+
+        taken[pass].push(counter);
+        passes[pass].push((index, counter));
+    }
+
+    Ok(passes)
+}
+
+/// The count of one event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassCount {
+    pub name: String,
+    /// The pass that counted the event, from 0
+    pub pass: usize,
+    pub count: u64,
+}
+
+/// The counts of all passes, in the order of the events
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PassReport {
+    pub counts: Vec<PassCount>,
+    /// How long every pass took
+    pub elapsed: Vec<Duration>,
+}
+
+impl std::fmt::Display for PassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (pass, elapsed) in self.elapsed.iter().enumerate() {
+            writeln!(f, "pass {pass}: {elapsed:?}")?;
+        }
+        for count in &self.counts {
+            writeln!(
+                f,
+                "{:>20}  {}  (pass {})",
+                count.count, count.name, count.pass
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `f` once per pass, counting the events of the pass, and return what the last run returned
+/// with the counts of all passes
+pub fn count_events<R>(
+    events: &[PassEvent],
+    f: impl FnMut() -> R,
+) -> std::io::Result<(R, PassReport)> {
+    count_events_with_layout(events, &layout()?, f)
+}
+
+/// Like `count_events`, for the counters of `layout`
+pub fn count_events_with_layout<R>(
+    events: &[PassEvent],
+    layout: &CounterLayout,
+    mut f: impl FnMut() -> R,
+) -> std::io::Result<(R, PassReport)> {
+    let passes = plan(events, layout)?;
+
+    let mut counts = vec![None; events.len()];
+    let mut elapsed = Vec::with_capacity(passes.len());
+    let mut value = None;
+    for (pass, placed) in passes.iter().enumerate() {
+        let start = Instant::now();
+        let (result, values) = run_pass(events, placed, layout, &mut f)?;
+        elapsed.push(start.elapsed());
+        value = Some(result);
+
+        for ((index, _), count) in placed.iter().zip(values) {
+            counts[*index] = Some(PassCount {
+                name: events[*index].name.clone(),
+                pass,
+                count,
+            });
+        }
+    }
+
+    let report = PassReport {
+        counts: counts.into_iter().flatten().collect(),
+        elapsed,
+    };
+    Ok((value.expect("there is always a pass"), report))
+}
+
+/// Run `f` with the events of one pass, and return their counts in the order of `placed`
+#[cfg(target_os = "linux")]
+fn run_pass<R>(
+    events: &[PassEvent],
+    placed: &[(usize, Counter)],
+    _layout: &CounterLayout,
+    f: &mut impl FnMut() -> R,
+) -> std::io::Result<(R, Vec<u64>)> {
+    // the kernel picks the counters
+    let encodings: Vec<Encoding> = placed
+        .iter()
+        .map(|(index, _)| events[*index].encoding)
+        .collect();
+
+    let mut counters = crate::raw::RawCounters::open(&encodings)?;
+    counters.count(f)
+}
+
+/// Run `f` with the events of one pass, and return their counts in the order of `placed`
+#[cfg(not(target_os = "linux"))]
+fn run_pass<R>(
+    events: &[PassEvent],
+    placed: &[(usize, Counter)],
+    layout: &CounterLayout,
+    f: &mut impl FnMut() -> R,
+) -> std::io::Result<(R, Vec<u64>)> {
+    let mut slots = Vec::new();
+    for (index, counter) in placed {
+        if let Counter::Configurable(counter) = *counter {
+            if slots.len() <= counter {
+                slots.resize(counter + 1, None);
+            }
+            slots[counter] = Some(events[*index].encoding);
+        }
+    }
+
+    let mut counters = crate::raw::RawCounters::open_slots(&slots)?;
+    let start = (counters.read()?, counters.fixed_values().to_vec());
+    let value = f();
+    let end = (counters.read()?, counters.fixed_values().to_vec());
+
+    let counts = placed
+        .iter()
+        .map(|(_, counter)| match *counter {
+            Counter::Fixed(i) => crate::counter_delta(start.1[i], end.1[i], layout.fixed_bits),
+            Counter::Configurable(i) => {
+                crate::counter_delta(start.0[i], end.0[i], layout.configurable_bits)
+            }
+        })
+        .collect::<std::io::Result<_>>()?;
+
+    Ok((value, counts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: CounterLayout = CounterLayout {
+        fixed: 2,
+        configurable: 4,
+        fixed_bits: 48,
+        configurable_bits: 48,
+    };
+
+    fn event(number: u16) -> PassEvent {
+        PassEvent::new(Encoding {
+            event: number,
+            ..Encoding::default()
+        })
+    }
+
+    fn fixed(number: u16, counter: usize) -> PassEvent {
+        PassEvent {
+            fixed: Some(counter),
+            ..event(number)
+        }
+    }
+
+    #[test]
+    fn events_that_fit_take_one_pass() {
+        let events: Vec<_> = (1..=4).map(event).collect();
+        let passes = plan(&events, &LAYOUT).unwrap();
+
+        assert_eq!(
+            passes,
+            [(0..4)
+                .map(|i| (i, Counter::Configurable(i)))
+                .collect::<Vec<_>>()]
+        );
+    }
+
+    #[test]
+    fn no_events_still_run_once() {
+        assert_eq!(plan(&[], &LAYOUT).unwrap(), [Vec::new()]);
+    }
+
+    #[test]
+    fn more_events_than_counters_are_split_into_passes() {
+        let events: Vec<_> = (1..=6).map(event).collect();
+        let passes = plan(&events, &LAYOUT).unwrap();
+
+        assert_eq!(passes.len(), 2);
+        assert_eq!(passes[0].len(), 4);
+        assert_eq!(
+            passes[1],
+            [(4, Counter::Configurable(0)), (5, Counter::Configurable(1))]
+        );
+    }
+
+    #[test]
+    fn every_pass_has_the_fixed_counters() {
+        // the configurable events need two passes, and so do the two events of fixed counter 0
+        let mut events: Vec<_> = (1..=5).map(event).collect();
+        events.push(fixed(0xa, 0));
+        events.push(fixed(0xb, 0));
+        events.push(fixed(0xc, 1));
+        let passes = plan(&events, &LAYOUT).unwrap();
+
+        assert_eq!(passes.len(), 2);
+        assert!(passes[0].contains(&(5, Counter::Fixed(0))));
+        assert!(passes[0].contains(&(7, Counter::Fixed(1))));
+        assert!(passes[1].contains(&(6, Counter::Fixed(0))));
+        assert!(passes[1].contains(&(4, Counter::Configurable(0))));
+    }
+
+    #[test]
+    fn events_go_where_their_mask_allows() {
+        let mut events: Vec<_> = (1..=2).map(event).collect();
+        events[0].counters = 0b1000;
+        events[1].counters = 0b1000;
+        let passes = plan(&events, &LAYOUT).unwrap();
+
+        assert_eq!(
+            passes,
+            [
+                vec![(0, Counter::Configurable(3))],
+                vec![(1, Counter::Configurable(3))]
+            ]
+        );
+    }
+
+    #[test]
+    fn a_fixed_counter_that_the_layout_lacks_is_configurable() {
+        let passes = plan(&[fixed(1, 2)], &LAYOUT).unwrap();
+        assert_eq!(passes, [vec![(0, Counter::Configurable(0))]]);
+    }
+
+    #[test]
+    fn an_event_that_no_counter_can_count_is_an_error() {
+        let mut events = vec![event(1)];
+        events[0].counters = 0b1_0000;
+        let e = plan(&events, &LAYOUT).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,640 @@
+//! The events of the PMUs that Linux knows about, for resolving event names such as
+//! `BR_MISP_RETIRED.ALL_BRANCHES` into the `type` and `config` of `perf_event_attr`. This is the
+//! Linux counterpart of the kpep database.
+//!
+//! Every PMU has a directory in `/sys/bus/event_source/devices`, with its perf type in `type`, the
+//! fields of its config in `format/*` (such as `config:8-15`), and some named events in `events/*`
+//! (such as `event=0xc4,umask=0x4`). The full event lists are only in the JSON files of perf
+//! (`tools/perf/pmu-events/arch/<arch>/<cpu>/*.json` in the kernel sources), which are read from a
+//! directory that has to be given.
+//!
+//! Both roots can be changed, so that the database can be read from copies, such as the ones in
+//! `fixtures/pmu`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::aliases::AliasTable;
+
+/// Where the database is read from
+#[derive(Debug, Clone)]
+pub struct Roots {
+    /// The directory with one directory per PMU
+    pub sysfs: PathBuf,
+    /// The directory with the perf JSON files of this CPU, if any
+    pub json: Option<PathBuf>,
+}
+
+impl Default for Roots {
+    fn default() -> Self {
+        Self {
+            sysfs: PathBuf::from("/sys/bus/event_source/devices"),
+            json: None,
+        }
+    }
+}
+
+/// The fields of `perf_event_attr` that select an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RawEvent {
+    pub type_: u32,
+    pub config: u64,
+    pub config1: u64,
+    pub config2: u64,
+}
+
+/// A PMU from sysfs
+#[derive(Debug, Clone, Default)]
+pub struct Pmu {
+    pub name: String,
+    /// The perf type of its events
+    pub type_: u32,
+    /// From term, such as "umask", to the bits that it sets
+    pub formats: BTreeMap<String, Format>,
+    /// From event name to terms, such as "event=0xc4,umask=0x4"
+    pub events: BTreeMap<String, String>,
+    /// The capabilities that the kernel exports in `caps`, such as "max_precise"
+    pub caps: BTreeMap<String, String>,
+}
+
+/// Where the value of a term goes, such as `config:0-7,32-35`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Format {
+    /// 0 for `config`, 1 for `config1`, ...
+    pub field: usize,
+    /// Ranges of bits, from the lowest bits of the value to the highest
+    pub bits: Vec<(u32, u32)>,
+}
+
+/// An event from the perf JSON files
+#[derive(Debug, Clone, Default)]
+pub struct JsonEvent {
+    pub name: String,
+    pub description: String,
+    /// The PMU that counts it, such as "cpu_core"; empty for the core PMU
+    pub pmu: String,
+    pub terms: Vec<(String, u64)>,
+    /// The `ConfigCode` of events that are given as a whole config word, such as the ones of Arm,
+    /// which goes into `config` as is
+    pub config: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EventDatabase {
+    /// Sorted by name
+    pub pmus: Vec<Pmu>,
+    pub json_events: Vec<JsonEvent>,
+}
+
+// `PERF_TYPE_RAW`, for core events when sysfs has no core PMU
+const PERF_TYPE_RAW: u32 = 4;
+
+/// The core PMUs that JSON events without a unit belong to, in order of preference
+const CORE_PMUS: [&str; 4] = ["cpu", "cpu_core", "armv8_pmuv3", "armv8_pmuv3_0"];
+
+/// The format of Intel and AMD core PMUs, for when sysfs does not say
+const X86_FORMATS: [(&str, &str); 10] = [
+    ("event", "config:0-7"),
+    ("umask", "config:8-15"),
+    ("edge", "config:18"),
+    ("pc", "config:19"),
+    ("any", "config:21"),
+    ("inv", "config:23"),
+    ("cmask", "config:24-31"),
+    ("offcore_rsp", "config1:0-63"),
+    ("ldlat", "config1:0-15"),
+    ("frontend", "config1:0-23"),
+];
+
+impl EventDatabase {
+    pub fn load(roots: &Roots) -> std::io::Result<Self> {
+        let mut database = Self::default();
+
+        match std::fs::read_dir(&roots.sysfs) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    // PMUs without a type cannot be used
+                    if let Some(pmu) = Pmu::load(name, &entry.path())? {
+                        database.pmus.push(pmu);
+                    }
+                }
+            }
+            // no perf support, or not Linux
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        database.pmus.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if let Some(json) = &roots.json {
+            let mut paths: Vec<PathBuf> = std::fs::read_dir(json)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            paths.sort();
+
+            for path in paths {
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+                {
+                    database.json_events.extend(JsonEvent::load(&path)?);
+                }
+            }
+        }
+
+        Ok(database)
+    }
+
+    pub fn pmu(&self, name: &str) -> Option<&Pmu> {
+        self.pmus.iter().find(|pmu| pmu.name == name)
+    }
+
+    /// The PMU of the CPU cores
+    pub fn core_pmu(&self) -> Option<&Pmu> {
+        CORE_PMUS.iter().find_map(|name| self.pmu(name))
+    }
+
+    /// Resolve an event name, either `pmu/name/` or a name from sysfs or from the JSON files. The
+    /// names from sysfs are tried first, the JSON names ignore case.
+    pub fn resolve(&self, name: &str) -> std::io::Result<RawEvent> {
+        let not_found = || {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("unknown event {name}"),
+            )
+        };
+
+        if let Some((pmu_name, event)) =
+            name.strip_suffix('/').and_then(|name| name.split_once('/'))
+        {
+            let pmu = self.pmu(pmu_name).ok_or_else(not_found)?;
+            let terms = pmu.events.get(event).ok_or_else(not_found)?;
+            return pmu.encode(&parse_terms(terms)?);
+        }
+
+        let core = self.core_pmu();
+        let sysfs = core
+            .into_iter()
+            .chain(self.pmus.iter())
+            .find_map(|pmu| Some((pmu, pmu.events.get(name)?)));
+        if let Some((pmu, terms)) = sysfs {
+            return pmu.encode(&parse_terms(terms)?);
+        }
+
+        let json = self
+            .json_events
+            .iter()
+            .find(|event| event.name.eq_ignore_ascii_case(name))
+            .ok_or_else(not_found)?;
+        self.encode_json(json)
+    }
+
+    /// The first of `names` that resolves, like `get_event` does with the kpep database
+    pub fn find<'a>(&self, names: &[&'a str]) -> Option<(&'a str, RawEvent)> {
+        names
+            .iter()
+            .find_map(|name| Some((*name, self.resolve(name).ok()?)))
+    }
+
+    /// Encode terms for the PMU of the CPU cores, or as a `PERF_TYPE_RAW` event of x86 when sysfs
+    /// has no core PMU
+    pub fn encode_core(&self, terms: &[(String, u64)]) -> std::io::Result<RawEvent> {
+        match self.core_pmu() {
+            Some(pmu) => pmu.encode(terms),
+            None => Pmu::x86_raw().encode(terms),
+        }
+    }
+
+    /// The first event of `alias` in the alias table that resolves, with its name
+    pub fn resolve_alias<'a>(
+        &self,
+        table: &'a AliasTable,
+        alias: &str,
+    ) -> Option<(&'a str, RawEvent)> {
+        self.find(&table.names(alias))
+    }
+
+    fn encode_json(&self, event: &JsonEvent) -> std::io::Result<RawEvent> {
+        let mut raw = self.encode_json_terms(event)?;
+        if let Some(config) = event.config {
+            raw.config |= config;
+        }
+        Ok(raw)
+    }
+
+    fn encode_json_terms(&self, event: &JsonEvent) -> std::io::Result<RawEvent> {
+        if event.pmu.is_empty() {
+            return self.encode_core(&event.terms);
+        }
+
+        // units are upper case in the JSON files, and may be a prefix of the PMUs
+        let unit = match event.pmu.as_str() {
+            "L3PMC" => "amd_l3".to_owned(),
+            "DFPMC" => "amd_df".to_owned(),
+            unit => unit.to_ascii_lowercase(),
+        };
+        let pmu = self
+            .pmu(&unit)
+            .or_else(|| self.pmu(&format!("uncore_{unit}")))
+            .or_else(|| self.pmu(&format!("uncore_{unit}_0")));
+
+        match pmu {
+            Some(pmu) => pmu.encode(&event.terms),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no PMU {} for event {}", event.pmu, event.name),
+            )),
+        }
+    }
+}
+
+impl Pmu {
+    fn load(name: String, path: &Path) -> std::io::Result<Option<Self>> {
+        let Ok(type_) = std::fs::read_to_string(path.join("type")) else {
+            return Ok(None);
+        };
+        let Ok(type_) = type_.trim().parse() else {
+            return Ok(None);
+        };
+
+        let mut pmu = Self {
+            name,
+            type_,
+            ..Self::default()
+        };
+
+        for (term, format) in read_files(&path.join("format"))? {
+            pmu.formats.insert(term, Format::parse(&format)?);
+        }
+
+        for (event, terms) in read_files(&path.join("events"))? {
+            // `.scale`, `.unit` and such describe the values of the events
+            if !event.contains('.') {
+                pmu.events.insert(event, terms);
+            }
+        }
+
+        pmu.caps = read_files(&path.join("caps"))?.into_iter().collect();
+
+        Ok(Some(pmu))
+    }
+
+    /// The number of general purpose counters, from `caps/num_counters` on kernels that export it
+    pub fn counters(&self) -> Option<usize> {
+        self.caps.get("num_counters")?.parse().ok()
+    }
+
+    /// A core PMU for `PERF_TYPE_RAW` events, with the formats of x86
+    fn x86_raw() -> Self {
+        let formats = X86_FORMATS
+            .iter()
+            .map(|(term, format)| (term.to_string(), Format::parse(format).unwrap()))
+            .collect();
+
+        Self {
+            name: "raw".to_owned(),
+            type_: PERF_TYPE_RAW,
+            formats,
+            events: BTreeMap::new(),
+            caps: BTreeMap::new(),
+        }
+    }
+
+    /// Put the values of the terms into the bits of the config fields that their formats give, or
+    /// fail if a value does not fit into the bits of its term
+    pub fn encode(&self, terms: &[(String, u64)]) -> std::io::Result<RawEvent> {
+        let mut fields = [0u64; 3];
+
+        for (term, value) in terms {
+            let format = self.formats.get(term).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("PMU {} has no term {term}", self.name),
+                )
+            })?;
+            let field = fields.get_mut(format.field).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("term {term} sets config{}", format.field),
+                )
+            })?;
+
+            let mut rest = *value;
+            for &(low, high) in format.bits.iter() {
+                let width = high - low + 1;
+                let mask = if width >= 64 {
+                    u64::MAX
+                } else {
+                    (1 << width) - 1
+                };
+                *field |= (rest & mask) << low;
+                rest = rest.checked_shr(width).unwrap_or(0);
+            }
+            if rest != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{term}={value:#x} does not fit into PMU {}", self.name),
+                ));
+            }
+        }
+
+        Ok(RawEvent {
+            type_: self.type_,
+            config: fields[0],
+            config1: fields[1],
+            config2: fields[2],
+        })
+    }
+}
+
+impl Format {
+    /// Parse a format such as `config:0-7,32-35` or `config1:5`
+    pub fn parse(format: &str) -> std::io::Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid format {format}"),
+            )
+        };
+
+        let (field, bits) = format.trim().split_once(':').ok_or_else(invalid)?;
+        let field = match field.strip_prefix("config").ok_or_else(invalid)? {
+            "" => 0,
+            n => n.parse().map_err(|_| invalid())?,
+        };
+
+        let bits = bits
+            .split(',')
+            .map(|range| {
+                let (low, high) = range.split_once('-').unwrap_or((range, range));
+                let low: u32 = low.parse().map_err(|_| invalid())?;
+                let high: u32 = high.parse().map_err(|_| invalid())?;
+                if low > high || high > 63 {
+                    return Err(invalid());
+                }
+                Ok((low, high))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { field, bits })
+    }
+}
+
+impl JsonEvent {
+    fn load(path: &Path) -> std::io::Result<Vec<Self>> {
+        let text = std::fs::read_to_string(path)?;
+        let entries: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_str(&text)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {e}", path.display()),
+                )
+            })?;
+
+        // metrics and such have no event name
+        Ok(entries.iter().filter_map(Self::from_entry).collect())
+    }
+
+    fn from_entry(entry: &serde_json::Map<String, serde_json::Value>) -> Option<Self> {
+        let field = |key: &str| entry.get(key).and_then(|value| value.as_str());
+        // numbers are strings such as "0xC5", sometimes with several values such as "0xB7,0xBB"
+        let number =
+            |key: &str| field(key).and_then(|value| parse_number(value.split(',').next()?));
+
+        let name = field("EventName")?.to_owned();
+
+        let config = number("ConfigCode");
+        let mut terms = Vec::new();
+        for (key, term) in [
+            ("EventCode", "event"),
+            ("UMask", "umask"),
+            ("CounterMask", "cmask"),
+            ("Invert", "inv"),
+            ("EdgeDetect", "edge"),
+            ("AnyThread", "any"),
+        ] {
+            match number(key) {
+                // zeros are left out, so that PMUs without the term can count the event
+                Some(0) if key != "EventCode" => {}
+                Some(value) => terms.push((term.to_owned(), value)),
+                None => {}
+            }
+        }
+
+        // extra registers of Intel core PMUs
+        let msr = match number("MSRIndex") {
+            Some(0x1a6 | 0x1a7) => Some("offcore_rsp"),
+            Some(0x3f6) => Some("ldlat"),
+            Some(0x3f7) => Some("frontend"),
+            _ => None,
+        };
+        if let (Some(term), Some(value)) = (msr, number("MSRValue")) {
+            terms.push((term.to_owned(), value));
+        }
+
+        if terms.is_empty() && config.is_none() {
+            // such as the `ArchStdEvent` references of Arm
+            return None;
+        }
+
+        Some(Self {
+            name,
+            description: field("BriefDescription").unwrap_or_default().to_owned(),
+            pmu: field("Unit").unwrap_or_default().to_owned(),
+            terms,
+            config,
+        })
+    }
+}
+
+/// The files of a directory with their contents; nothing if the directory does not exist
+fn read_files(dir: &Path) -> std::io::Result<Vec<(String, String)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let contents = std::fs::read_to_string(entry.path())?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        files.push((name, contents.trim().to_owned()));
+    }
+
+    Ok(files)
+}
+
+/// Parse terms such as `event=0xc4,umask=0x4,edge`, where a term without a value is 1
+pub fn parse_terms(terms: &str) -> std::io::Result<Vec<(String, u64)>> {
+    terms
+        .split(',')
+        .map(str::trim)
+        .filter(|term| !term.is_empty())
+        .map(|term| {
+            let (name, value) = term.split_once('=').unwrap_or((term, "1"));
+            let value = parse_number(value).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid value in {term}"),
+                )
+            })?;
+            Ok((name.trim().to_owned(), value))
+        })
+        .collect()
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// The `events` subcommand: `events [--sysfs DIR] [--json DIR] [NAME...]`, which resolves the
+/// given events, or lists all of them
+pub fn main(args: &[String]) -> ! {
+    let usage = || -> ! {
+        eprintln!("usage: events [--sysfs DIR] [--json DIR] [NAME...]");
+        std::process::exit(2);
+    };
+
+    let mut roots = Roots::default();
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sysfs" => roots.sysfs = args.next().unwrap_or_else(|| usage()).into(),
+            "--json" => roots.json = Some(args.next().unwrap_or_else(|| usage()).into()),
+            _ if arg.starts_with('-') => usage(),
+            _ => names.push(arg.as_str()),
+        }
+    }
+
+    let database = match EventDatabase::load(&roots) {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("Failed to read the event database: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let print = |name: &str, event: &RawEvent| {
+        println!(
+            "{name:<40} type={} config={:#x} config1={:#x} config2={:#x}",
+            event.type_, event.config, event.config1, event.config2
+        );
+    };
+
+    if !names.is_empty() {
+        let mut failed = false;
+        for name in names {
+            match database.resolve(name) {
+                Ok(event) => print(name, &event),
+                Err(e) => {
+                    eprintln!("{e}");
+                    failed = true;
+                }
+            }
+        }
+        std::process::exit(failed as i32);
+    }
+
+    for pmu in database.pmus.iter() {
+        for event in pmu.events.keys() {
+            let name = format!("{}/{event}/", pmu.name);
+            match database.resolve(&name) {
+                Ok(raw) => print(&name, &raw),
+                Err(e) => println!("{name:<40} {e}"),
+            }
+        }
+    }
+    for event in database.json_events.iter() {
+        match database.encode_json(event) {
+            Ok(raw) => print(&event.name, &raw),
+            Err(e) => println!("{:<40} {e}", event.name),
+        }
+    }
+
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(sysfs: &str, json: &str) -> EventDatabase {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/pmu");
+        let roots = Roots {
+            sysfs: fixtures.join(sysfs),
+            json: Some(fixtures.join("json").join(json)),
+        };
+        EventDatabase::load(&roots).unwrap()
+    }
+
+    #[test]
+    fn haswell_events_encode() {
+        let database = database("sysfs", "haswell");
+
+        let misses = database.resolve("LONGEST_LAT_CACHE.MISS").unwrap();
+        assert_eq!((misses.type_, misses.config), (4, 0x412e));
+
+        let walks = database.resolve("DTLB_LOAD_MISSES.WALK_COMPLETED").unwrap();
+        assert_eq!((walks.type_, walks.config), (4, 0xe08));
+    }
+
+    #[test]
+    fn zen4_events_encode() {
+        let database = database("sysfs-zen4", "zen4");
+
+        // the event number is split between bits 0-7 and 32-35
+        let stalls = database
+            .resolve("de_no_dispatch_per_slot.no_ops_from_frontend")
+            .unwrap();
+        assert_eq!((stalls.type_, stalls.config), (4, 0x1000001a0));
+
+        let l3 = database.resolve("l3_lookup_state.l3_miss").unwrap();
+        assert_eq!((l3.type_, l3.config), (11, 0x104));
+    }
+
+    #[test]
+    fn a_config_code_is_the_config_word() {
+        let database = database("sysfs", "haswell");
+        let event = JsonEvent {
+            name: "cycles".to_owned(),
+            config: Some(0x11),
+            ..JsonEvent::default()
+        };
+        assert_eq!(database.encode_json(&event).unwrap().config, 0x11);
+    }
+
+    #[test]
+    fn values_that_do_not_fit_are_rejected() {
+        let database = database("sysfs", "haswell");
+        let error = database
+            .encode_core(&[("umask".to_owned(), 0x100)])
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        let fits = database.encode_core(&[("event".to_owned(), 0xff)]).unwrap();
+        assert_eq!(fits.config, 0xff);
+    }
+
+    #[test]
+    fn counters_come_from_the_caps() {
+        let zen4 = database("sysfs-zen4", "zen4");
+        assert_eq!(zen4.core_pmu().unwrap().counters(), Some(6));
+        assert_eq!(zen4.core_pmu().unwrap().caps["pmu_name"], "zen");
+
+        // older kernels only have the other caps
+        let haswell = database("sysfs", "haswell");
+        assert_eq!(haswell.core_pmu().unwrap().counters(), None);
+        assert_eq!(haswell.core_pmu().unwrap().caps["max_precise"], "3");
+    }
+}
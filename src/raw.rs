@@ -0,0 +1,601 @@
+//! Counting events that are given by their encoding rather than by name, such as `r01c4` or
+//! `event=0xc4,umask=0x01,cmask=1,inv`, optionally followed by the modes to count in, like
+//! `r01c4:u` (see `modes`).
+//!
+//! The `r` form is the config word of the core PMU in hex, like in `perf stat -e r01c4`. On x86
+//! it may only set the fields that can also be given as terms: `event` (bits 0-7), `umask` (8-15),
+//! `edge` (18), `inv` (23) and `cmask` (24-31). On Arm the config word is only the event number,
+//! and the other terms are not available.
+//!
+//! On Linux the events are opened for the core PMU from sysfs, or as `PERF_TYPE_RAW` events. On
+//! macOS they are written to the config registers of the configurable counters with
+//! `kpc_set_config`, which replaces the events that the counters were set up with, also for other
+//! collectors in the process; the readers of the `session` return an error while they do. Some
+//! events of Apple CPUs can only be counted by some of the counters (see the counter masks of the
+//! kpep database); the events are put into the counters in the order in which they are given, and
+//! an event that its counter cannot count is an error. The config and the counting classes that the
+//! counters had before are restored when the `RawCounters` are dropped, so counters that are open
+//! at the same time have to be dropped in the reverse order of opening.
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use crate::modes::Modes;
+
+/// The encoding of an event of the core PMU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Encoding {
+    pub event: u16,
+    pub umask: u8,
+    /// Count only cycles in which the event happens at least this often
+    pub cmask: u8,
+    /// Count the rising edges of the condition, rather than the cycles in which it holds
+    pub edge: bool,
+    /// Invert the comparison with `cmask`
+    pub inv: bool,
+    pub modes: Modes,
+}
+
+// the fields of the x86 config word that `Encoding` covers
+const X86_EDGE: u64 = 1 << 18;
+const X86_INV: u64 = 1 << 23;
+const X86_FIELDS: u64 = 0xff | 0xff << 8 | X86_EDGE | X86_INV | 0xff << 24;
+
+impl Encoding {
+    /// Decode the config word of an `r` event
+    pub fn from_config(config: u64) -> std::io::Result<Self> {
+        if cfg!(target_arch = "aarch64") {
+            let event = u16::try_from(config)
+                .map_err(|_| invalid(format!("r{config:x} is not an event number")))?;
+            return Ok(Self {
+                event,
+                ..Self::default()
+            });
+        }
+
+        if config & !X86_FIELDS != 0 {
+            return Err(invalid(format!(
+                "r{config:x} sets bits {:#x}, which are not event, umask, edge, inv or cmask",
+                config & !X86_FIELDS
+            )));
+        }
+
+        Ok(Self {
+            event: (config & 0xff) as u16,
+            umask: (config >> 8) as u8,
+            cmask: (config >> 24) as u8,
+            edge: config & X86_EDGE != 0,
+            inv: config & X86_INV != 0,
+            modes: Modes::ALL,
+        })
+    }
+
+    /// Check that the CPU has the fields that are set
+    pub fn validate(&self) -> std::io::Result<()> {
+        self.modes
+            .validate()
+            .map_err(|e| invalid(format!("{self}: {e}")))?;
+
+        if cfg!(target_arch = "aarch64") {
+            if self.umask != 0 || self.cmask != 0 || self.edge || self.inv {
+                return Err(invalid(format!(
+                    "{self}: Arm events only have an event number"
+                )));
+            }
+            // the event field of the config registers of Apple CPUs has 8 bits
+            if cfg!(target_os = "macos") && self.event > 0xff {
+                return Err(invalid(format!("{self}: the event number has 8 bits")));
+            }
+        } else if self.event > 0xff {
+            return Err(invalid(format!("{self}: the event number has 8 bits")));
+        }
+
+        Ok(())
+    }
+
+    /// The terms for the formats of the core PMU in sysfs
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn terms(&self) -> Vec<(String, u64)> {
+        let mut terms = vec![("event".to_owned(), self.event as u64)];
+        for (term, value) in [
+            ("umask", self.umask as u64),
+            ("cmask", self.cmask as u64),
+            ("edge", self.edge as u64),
+            ("inv", self.inv as u64),
+        ] {
+            // zeros are left out, so that PMUs without the term can count the event
+            if value != 0 {
+                terms.push((term.to_owned(), value));
+            }
+        }
+        terms
+    }
+
+    /// The value of a config register for `kpc_set_config`, counting in the modes of the event
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    fn kpc_config(&self) -> u64 {
+        let config = if cfg!(target_arch = "aarch64") {
+            self.event as u64
+        } else {
+            self.event as u64
+                | (self.umask as u64) << 8
+                | if self.edge { X86_EDGE } else { 0 }
+                | crate::KPC_X86_EN
+                | if self.inv { X86_INV } else { 0 }
+                | (self.cmask as u64) << 24
+        };
+        crate::modes::kpc_config(config, self.modes)
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = std::io::Error;
+
+    /// Parse `rHEX`, or terms such as `event=0xc4,umask=0x01,cmask=1,inv`, and the modifiers of
+    /// the modes after a `:`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (spec, modes) = match spec.trim().rsplit_once(':') {
+            Some((spec, modifiers)) => (spec, modifiers.parse()?),
+            None => (spec.trim(), Modes::ALL),
+        };
+
+        if let Some(hex) = spec.strip_prefix('r') {
+            if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                let config =
+                    u64::from_str_radix(hex, 16).map_err(|e| invalid(format!("{spec}: {e}")))?;
+                let encoding = Self {
+                    modes,
+                    ..Self::from_config(config)?
+                };
+                encoding.validate()?;
+                return Ok(encoding);
+            }
+        }
+
+        let mut encoding = Self {
+            modes,
+            ..Self::default()
+        };
+        let mut has_event = false;
+        for (term, value) in crate::pmu::parse_terms(spec)? {
+            let too_large = || invalid(format!("{spec}: {term}={value:#x} is too large"));
+            match term.as_str() {
+                "event" => {
+                    encoding.event = value.try_into().map_err(|_| too_large())?;
+                    has_event = true;
+                }
+                "umask" => encoding.umask = value.try_into().map_err(|_| too_large())?,
+                "cmask" => encoding.cmask = value.try_into().map_err(|_| too_large())?,
+                "edge" => encoding.edge = value != 0,
+                "inv" => encoding.inv = value != 0,
+                _ => return Err(invalid(format!("{spec}: unknown term {term}"))),
+            }
+        }
+
+        if !has_event {
+            return Err(invalid(format!("{spec}: no event")));
+        }
+        encoding.validate()?;
+
+        Ok(encoding)
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "event={:#x}", self.event)?;
+        if self.umask != 0 {
+            write!(f, ",umask={:#x}", self.umask)?;
+        }
+        if self.cmask != 0 {
+            write!(f, ",cmask={}", self.cmask)?;
+        }
+        if self.edge {
+            write!(f, ",edge")?;
+        }
+        if self.inv {
+            write!(f, ",inv")?;
+        }
+        if self.modes != Modes::ALL {
+            write!(f, ":{}", self.modes)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check(name: &str, ret: i32) -> std::io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        ret => Err(std::io::Error::other(format!("Failed {name}: {ret}"))),
+    }
+}
+
+/// Counters of the calling thread that count raw events. They count the thread that opened
+/// them, so they cannot be sent to other threads.
+pub struct RawCounters {
+    #[cfg(target_os = "linux")]
+    events: Vec<crate::linux::PerfEvent>,
+
+    #[cfg(not(target_os = "linux"))]
+    collector: &'static crate::EventCollector,
+    /// The number of fixed counters, which come before the configurable ones
+    #[cfg(not(target_os = "linux"))]
+    fixed: usize,
+    #[cfg(not(target_os = "linux"))]
+    count: usize,
+    #[cfg(not(target_os = "linux"))]
+    raw: [u64; crate::KPC_MAX_COUNTERS],
+    /// The width of the configurable counters
+    #[cfg(not(target_os = "linux"))]
+    bits: u32,
+    /// The config registers, counting and thread counting classes to restore on drop
+    #[cfg(not(target_os = "linux"))]
+    saved: (Vec<u64>, u32, u32),
+
+    _thread: PhantomData<*const ()>,
+}
+
+impl RawCounters {
+    #[cfg(target_os = "linux")]
+    pub fn open(events: &[Encoding]) -> std::io::Result<Self> {
+        use crate::linux::{PerfEvent, PerfEventAttr, Target};
+        use crate::pmu::{EventDatabase, Roots};
+
+        let database = EventDatabase::load(&Roots::default())?;
+
+        let mut opened = Vec::with_capacity(events.len());
+        for encoding in events {
+            encoding.validate()?;
+            let raw = database.encode_core(&encoding.terms())?;
+
+            let target = Target {
+                pid: 0,
+                cpu: -1,
+                inherit: false,
+                enable_on_exec: false,
+            };
+            let mut attr = PerfEventAttr::from_raw(&raw);
+            attr.set_modes(encoding.modes);
+            let event = PerfEvent::open(attr, target)?;
+            event.enable()?;
+            opened.push(event);
+        }
+
+        Ok(Self {
+            events: opened,
+            _thread: PhantomData,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(events: &[Encoding]) -> std::io::Result<Self> {
+        let slots: Vec<_> = events.iter().copied().map(Some).collect();
+        Self::open_slots(&slots)
+    }
+
+    /// Put every event into the configurable counter with its index, leaving the counters of
+    /// `None` unused, for events that only some of the counters can count
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn open_slots(slots: &[Option<Encoding>]) -> std::io::Result<Self> {
+        let collector = crate::session::Session::global()?.collector();
+        let kperf = &collector.kperf_symbols;
+        let configurable = crate::KPC_CLASS_CONFIGURABLE_MASK as u32;
+
+        let _pmu = crate::lock_pmu();
+        let (fixed, config) = Self::config(collector, slots)?;
+
+        let mut saved_config = vec![0u64; config.len()];
+        let saved = unsafe {
+            check(
+                "get kpc config",
+                (kperf.kpc_get_config)(configurable, saved_config.as_mut_ptr()),
+            )?;
+            (
+                saved_config,
+                (kperf.kpc_get_counting)(),
+                (kperf.kpc_get_thread_counting)(),
+            )
+        };
+
+        // the config may have changed even if programming failed halfway
+        let programmed = Self::program(collector, &config);
+        crate::pmu_reprogrammed();
+        programmed?;
+        crate::pmu_borrowed(true);
+
+        Ok(Self {
+            collector,
+            fixed,
+            count: slots.len(),
+            raw: [0; crate::KPC_MAX_COUNTERS],
+            bits: match collector.counter_layout() {
+                Some(layout) if layout.configurable_bits != 0 => layout.configurable_bits,
+                _ => 64,
+            },
+            saved,
+            _thread: PhantomData,
+        })
+    }
+
+    /// Put other events into the counters, like `open_slots`. The config that is restored on drop
+    /// stays the one from before the counters were opened.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn reprogram(&mut self, slots: &[Option<Encoding>]) -> std::io::Result<()> {
+        let _pmu = crate::lock_pmu();
+        let (_, config) = Self::config(self.collector, slots)?;
+        let programmed = Self::program(self.collector, &config);
+        crate::pmu_reprogrammed();
+        programmed?;
+        self.count = slots.len();
+        Ok(())
+    }
+
+    /// The number of fixed counters, and the config registers of the configurable counters for
+    /// `slots`
+    #[cfg(not(target_os = "linux"))]
+    fn config(
+        collector: &crate::EventCollector,
+        slots: &[Option<Encoding>],
+    ) -> std::io::Result<(usize, Vec<u64>)> {
+        use crate::{KPC_CLASS_CONFIGURABLE_MASK, KPC_CLASS_FIXED_MASK};
+
+        let kperf = &collector.kperf_symbols;
+        let configurable = KPC_CLASS_CONFIGURABLE_MASK as u32;
+        let (fixed, counter_count, config_count) = unsafe {
+            (
+                (kperf.kpc_get_counter_count)(KPC_CLASS_FIXED_MASK as u32) as usize,
+                (kperf.kpc_get_counter_count)(configurable) as usize,
+                (kperf.kpc_get_config_count)(configurable) as usize,
+            )
+        };
+
+        if slots.len() > counter_count.min(config_count) {
+            return Err(invalid(format!(
+                "{} events, but only {counter_count} configurable counters",
+                slots.len()
+            )));
+        }
+
+        // the unused counters count nothing
+        let mut config = vec![0u64; config_count];
+        for (counter, (register, encoding)) in config.iter_mut().zip(slots).enumerate() {
+            if let Some(encoding) = encoding {
+                encoding.validate()?;
+                // events that the database does not have may count anywhere
+                match collector.event_counters(encoding.event, encoding.umask) {
+                    Some(mask) if mask & 1u32.checked_shl(counter as u32).unwrap_or(0) == 0 => {
+                        return Err(invalid(format!(
+                            "{encoding}: configurable counter {counter} cannot count it (mask {mask:#x})"
+                        )));
+                    }
+                    _ => {}
+                }
+                *register = encoding.kpc_config();
+            }
+        }
+
+        Ok((fixed, config))
+    }
+
+    /// Write `config` to the configurable counters, and count with the fixed and configurable ones
+    #[cfg(not(target_os = "linux"))]
+    fn program(collector: &crate::EventCollector, config: &[u64]) -> std::io::Result<()> {
+        use crate::{KPC_CLASS_CONFIGURABLE_MASK, KPC_CLASS_FIXED_MASK};
+
+        let kperf = &collector.kperf_symbols;
+        let configurable = KPC_CLASS_CONFIGURABLE_MASK as u32;
+        let classes = configurable | KPC_CLASS_FIXED_MASK as u32;
+
+        unsafe {
+            check("force all ctrs", (kperf.kpc_force_all_ctrs_set)(1))?;
+            check(
+                "set kpc config",
+                (kperf.kpc_set_config)(configurable, config.as_ptr()),
+            )?;
+            check("set counting", (kperf.kpc_set_counting)(classes))?;
+            check(
+                "set thread counting",
+                (kperf.kpc_set_thread_counting)(classes),
+            )
+        }
+    }
+
+    /// The current values of the events, in the order in which they were given
+    pub fn read(&mut self) -> std::io::Result<Vec<u64>> {
+        #[cfg(target_os = "linux")]
+        {
+            self.events.iter().map(|event| event.read()).collect()
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let kperf = &self.collector.kperf_symbols;
+            let ret = unsafe {
+                (kperf.kpc_get_thread_counters)(0, self.raw.len() as u32, self.raw.as_mut_ptr())
+            };
+            if ret != 0 {
+                return Err(std::io::Error::other(format!(
+                    "Failed to get thread counters: {ret}"
+                )));
+            }
+
+            Ok(self.raw[self.fixed..][..self.count].to_vec())
+        }
+    }
+
+    /// The values of the fixed counters at the last `read`
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn fixed_values(&self) -> &[u64] {
+        &self.raw[..self.fixed]
+    }
+
+    /// The width of the counters, which the kernel extends to 64 bits on Linux
+    pub(crate) fn bits(&self) -> u32 {
+        #[cfg(target_os = "linux")]
+        {
+            64
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.bits
+        }
+    }
+
+    /// Run `f`, and return how often every event happened while it ran
+    pub fn count<R>(&mut self, f: impl FnOnce() -> R) -> std::io::Result<(R, Vec<u64>)> {
+        let start = self.read()?;
+        let value = f();
+        let end = self.read()?;
+
+        let counts = end
+            .iter()
+            .zip(start.iter())
+            .map(|(end, start)| crate::counter_delta(*start, *end, self.bits()))
+            .collect::<std::io::Result<_>>()?;
+
+        Ok((value, counts))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Drop for RawCounters {
+    /// Put back the events and counting classes that the counters had before they were opened
+    fn drop(&mut self) {
+        let kperf = &self.collector.kperf_symbols;
+        let configurable = crate::KPC_CLASS_CONFIGURABLE_MASK as u32;
+        let (config, counting, thread_counting) = &self.saved;
+
+        let _pmu = crate::lock_pmu();
+        let ret = unsafe {
+            match (kperf.kpc_set_config)(configurable, config.as_ptr()) {
+                0 => match (kperf.kpc_set_counting)(*counting) {
+                    0 => (kperf.kpc_set_thread_counting)(*thread_counting),
+                    ret => ret,
+                },
+                ret => ret,
+            }
+        };
+        if ret != 0 {
+            eprintln!("Failed to restore the kpc config: {ret}");
+        }
+        crate::pmu_reprogrammed();
+        crate::pmu_borrowed(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoding(event: u16, umask: u8) -> Encoding {
+        Encoding {
+            event,
+            umask,
+            ..Encoding::default()
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn parse_x86_encodings() {
+        let cases = [
+            ("r01c4", Some(encoding(0xc4, 0x01))),
+            ("  r3c  ", Some(encoding(0x3c, 0))),
+            (
+                "r1a8001b1",
+                Some(Encoding {
+                    cmask: 0x1a,
+                    inv: true,
+                    ..encoding(0xb1, 0x01)
+                }),
+            ),
+            ("event=0xc4,umask=0x01", Some(encoding(0xc4, 0x01))),
+            (
+                "event=0xa3,umask=0x4,cmask=4,edge,inv",
+                Some(Encoding {
+                    cmask: 4,
+                    edge: true,
+                    inv: true,
+                    ..encoding(0xa3, 0x04)
+                }),
+            ),
+            (
+                "r01c4:u",
+                Some(Encoding {
+                    modes: Modes::USER,
+                    ..encoding(0xc4, 0x01)
+                }),
+            ),
+            (
+                "event=0x3c:k",
+                Some(Encoding {
+                    modes: Modes::KERNEL,
+                    ..encoding(0x3c, 0)
+                }),
+            ),
+            // bits that are not event, umask, edge, inv or cmask
+            ("r100000000", None),
+            ("r10000", None),
+            // more than 64 bits
+            ("r10000000000000000", None),
+            ("event=0x100", None),
+            ("event=0xc4,umask=0x100", None),
+            ("event=0xc4,period=1000", None),
+            ("umask=0x01", None),
+            ("r01c4:x", None),
+            ("r", None),
+            ("", None),
+        ];
+
+        for (spec, expected) in cases {
+            match expected {
+                Some(expected) => assert_eq!(spec.parse::<Encoding>().unwrap(), expected, "{spec}"),
+                None => assert!(spec.parse::<Encoding>().is_err(), "{spec}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn parse_arm_encodings() {
+        let cases = [
+            ("r11", Some(encoding(0x11, 0))),
+            ("event=0x8", Some(encoding(0x8, 0))),
+            (
+                "r11:u",
+                Some(Encoding {
+                    modes: Modes::USER,
+                    ..encoding(0x11, 0)
+                }),
+            ),
+            ("event=0x11,umask=0x1", None),
+            ("event=0x11,foo=1", None),
+            ("r10000000000000000", None),
+            ("", None),
+        ];
+
+        for (spec, expected) in cases {
+            match expected {
+                Some(expected) => assert_eq!(spec.parse::<Encoding>().unwrap(), expected, "{spec}"),
+                None => assert!(spec.parse::<Encoding>().is_err(), "{spec}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn encodings_display_as_they_parse() {
+        for spec in [
+            "event=0xc4,umask=0x1",
+            "event=0xa3,umask=0x4,cmask=4,edge,inv:u",
+        ] {
+            assert_eq!(spec.parse::<Encoding>().unwrap().to_string(), spec);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! Sampling the counters of a process on a timer, for a time series of what it does rather than
+//! a single total.
+//!
+//! On macOS a kperf timer fires an action that records the counters, and optionally the user space
+//! call stack, of the threads of the process into the kdebug trace buffer. Only the thread that is
+//! running when the timer fires is sampled, unless `every_thread` makes it a PET ("profile every
+//! thread") timer. This needs root.
+//!
+//! On Linux every thread of the process gets a CPU clock event that leads a group of counters, and
+//! that writes the values of the group and the call stack to a ring buffer every period. Threads
+//! that are created after sampling starts are not sampled.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::{interval, Counter, CounterValues, PerformanceCounters};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The time between samples
+    pub period: Duration,
+    /// Also record the user space call stack of every sample
+    pub call_stacks: bool,
+    /// On macOS, sample every thread of the process rather than only the ones that are running
+    pub every_thread: bool,
+    /// Stop after this long; otherwise sample until Ctrl-C or until the process exits
+    pub duration: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_millis(1),
+            call_stacks: false,
+            every_thread: false,
+            duration: None,
+        }
+    }
+}
+
+/// The events counted on one thread since its previous sample
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Sample {
+    /// Nanoseconds since the first sample
+    pub time_ns: u64,
+    pub thread: u64,
+    pub counters: PerformanceCounters,
+    /// The return addresses of the user space call stack, innermost first, when asked for
+    pub stack: Vec<u64>,
+}
+
+/// Turns the running totals of every thread into deltas
+#[derive(Default)]
+struct Deltas {
+    first_time: Option<u64>,
+    previous: HashMap<u64, CounterValues>,
+}
+
+impl Deltas {
+    /// The sample since the previous one of `thread`, or an error if its counters went backwards
+    fn sample(
+        &mut self,
+        time_ns: u64,
+        thread: u64,
+        counters: CounterValues,
+        stack: Vec<u64>,
+    ) -> std::io::Result<Sample> {
+        let first_time = *self.first_time.get_or_insert(time_ns);
+        let previous = self.previous.insert(thread, counters).unwrap_or_default();
+
+        Ok(Sample {
+            time_ns: time_ns.saturating_sub(first_time),
+            thread,
+            counters: counters.delta(&previous)?.to_counters(),
+            stack,
+        })
+    }
+}
+
+/// How often the samples are collected from the kernel buffers
+pub(crate) const DRAIN_PERIOD: Duration = Duration::from_millis(50);
+
+/// Sample the process `pid` until the duration has passed, Ctrl-C is pressed, or the process exits
+pub fn sample(pid: i32, options: &Options) -> std::io::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+
+    #[cfg(target_os = "linux")]
+    {
+        use crate::linux::{threads, SampledThread};
+
+        let mut deltas = Deltas::default();
+
+        let mut sampled = Vec::new();
+        for tid in threads(pid)? {
+            match SampledThread::open(tid, options.period, options.call_stacks) {
+                Ok(thread) => sampled.push(thread),
+                // the thread exited in the meantime
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let start = Instant::now();
+        for thread in sampled.iter() {
+            thread.enable()?;
+        }
+
+        let mut drain = || -> std::io::Result<()> {
+            let mut result = Ok(());
+            for thread in sampled.iter_mut() {
+                thread.drain(|raw| {
+                    match deltas.sample(raw.time, raw.tid as u64, raw.counters, raw.stack) {
+                        Ok(sample) => samples.push(sample),
+                        Err(e) => result = Err(e),
+                    }
+                });
+            }
+            result
+        };
+
+        interval::run(
+            start,
+            Some(DRAIN_PERIOD),
+            options.duration,
+            || !interval::process_alive(pid),
+            |_| drain(),
+        )?;
+        drain()?;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let mut collector = crate::EventCollector::load();
+        if !collector.has_events() {
+            return Err(std::io::Error::other(
+                "performance counters are not available",
+            ));
+        }
+
+        let session = KperfSession::start(&collector, pid, options)?;
+        let mut decoder = TraceDecoder::default();
+        let mut entries = Vec::new();
+
+        let mut drain = || -> std::io::Result<()> {
+            crate::kdebug::read(&mut entries, KperfSession::BUFFER_ENTRIES)?;
+            decoder.decode(&collector, &entries, &mut samples)
+        };
+
+        interval::run(
+            Instant::now(),
+            Some(DRAIN_PERIOD),
+            options.duration,
+            || !interval::process_alive(pid),
+            |_| drain(),
+        )?;
+        drain()?;
+
+        drop(session);
+    }
+
+    Ok(samples)
+}
+
+/// Turns the kdebug trace entries of kperf into samples. The counters and call stack of a sample
+/// are spread over several entries, and so can be spread over two reads of the trace.
+#[cfg(not(target_os = "linux"))]
+#[derive(Default)]
+pub(crate) struct TraceDecoder {
+    pending: HashMap<u64, PendingSample>,
+    deltas: Deltas,
+}
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Default)]
+struct PendingSample {
+    counters: Vec<u64>,
+    stack: Vec<u64>,
+    stack_len: usize,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl TraceDecoder {
+    pub fn decode(
+        &mut self,
+        collector: &crate::EventCollector,
+        entries: &[crate::kdebug::KdBuf],
+        samples: &mut Vec<Sample>,
+    ) -> std::io::Result<()> {
+        use crate::kdebug;
+
+        for entry in entries {
+            let thread = entry.arg5 as u64;
+            let state = self.pending.entry(thread).or_default();
+
+            match entry.event() {
+                kdebug::PERF_CS_UHDR => {
+                    state.stack.clear();
+                    state.stack_len = entry.arg2;
+                }
+                kdebug::PERF_CS_UDATA => {
+                    let missing = state.stack_len.saturating_sub(state.stack.len());
+                    state.stack.extend(entry.args().into_iter().take(missing));
+                }
+                kdebug::PERF_KPC_DATA_THREAD => {
+                    if entry.function() & kdebug::DBG_FUNC_START != 0 {
+                        state.counters.clear();
+                    }
+                    state.counters.extend(entry.args());
+
+                    if entry.function() & kdebug::DBG_FUNC_END != 0 {
+                        let counters = collector.apple_events.values_from_raw(&state.counters);
+                        let time =
+                            unsafe { (collector.kperf_symbols.kperf_ticks_to_ns)(entry.timestamp) };
+                        let stack = std::mem::take(&mut state.stack);
+                        samples.push(self.deltas.sample(time, thread, counters, stack)?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The kperf timer and action, and the trace buffer, for as long as sampling runs
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct KperfSession<'a> {
+    kperf: &'a crate::KperfSymbols<'static>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<'a> KperfSession<'a> {
+    pub const BUFFER_ENTRIES: usize = 1_000_000;
+    const ACTION: u32 = 1;
+    const TIMER: u32 = 1;
+
+    pub fn start(
+        collector: &'a crate::EventCollector,
+        pid: i32,
+        options: &Options,
+    ) -> std::io::Result<Self> {
+        use crate::kdebug;
+
+        let check = |name: &str, ret: i32| match ret {
+            0 => Ok(()),
+            ret => Err(std::io::Error::other(format!("Failed {name}: {ret}"))),
+        };
+
+        let mut events = vec![kdebug::PERF_KPC_DATA_THREAD];
+        let mut samplers = crate::KPERF_SAMPLER_PMC_THREAD;
+        if options.call_stacks {
+            events.extend([kdebug::PERF_CS_UHDR, kdebug::PERF_CS_UDATA]);
+            samplers |= crate::KPERF_SAMPLER_USTACK;
+        }
+
+        kdebug::start(Self::BUFFER_ENTRIES, &events)?;
+        // from here on, dropping the session cleans up
+        let session = Self {
+            kperf: &collector.kperf_symbols,
+        };
+        let kperf = session.kperf;
+        // declared after the session, so that it is released before the session cleans up
+        let _pmu = crate::lock_pmu();
+
+        unsafe {
+            let period = (kperf.kperf_ns_to_ticks)(options.period.as_nanos() as u64);
+
+            check(
+                "set action count",
+                (kperf.kperf_action_count_set)(crate::KPERF_ACTION_MAX),
+            )?;
+            check(
+                "set action samplers",
+                (kperf.kperf_action_samplers_set)(Self::ACTION, samplers),
+            )?;
+            check(
+                "set action filter",
+                (kperf.kperf_action_filter_set_by_pid)(Self::ACTION, pid),
+            )?;
+            check(
+                "set timer count",
+                (kperf.kperf_timer_count_set)(crate::KPERF_TIMER_MAX),
+            )?;
+            check(
+                "set timer period",
+                (kperf.kperf_timer_period_set)(Self::TIMER, period),
+            )?;
+            check(
+                "set timer action",
+                (kperf.kperf_timer_action_set)(Self::TIMER, Self::ACTION),
+            )?;
+            if options.every_thread {
+                check("set PET timer", (kperf.kperf_timer_pet_set)(Self::TIMER))?;
+            }
+            check("start sampling", (kperf.kperf_sample_set)(1))?;
+        }
+
+        Ok(session)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Drop for KperfSession<'_> {
+    fn drop(&mut self) {
+        let _pmu = crate::lock_pmu();
+        unsafe {
+            (self.kperf.kperf_sample_set)(0);
+            (self.kperf.kperf_reset)();
+        }
+        crate::kdebug::stop();
+    }
+}
+
+/// Write the samples as CSV, one row per sample, with the call stack as `;` separated addresses
+pub fn write_csv(w: &mut impl Write, samples: &[Sample]) -> std::io::Result<()> {
+    write!(w, "time_ns,thread")?;
+    for counter in Counter::ALL {
+        write!(w, ",{}", counter.name())?;
+    }
+    writeln!(w, ",stack")?;
+
+    for sample in samples {
+        write!(w, "{},{}", sample.time_ns, sample.thread)?;
+        for counter in Counter::ALL {
+            write!(w, ",{}", counter.get(&sample.counters))?;
+        }
+
+        let stack: Vec<String> = sample.stack.iter().map(|ip| format!("{ip:#x}")).collect();
+        writeln!(w, ",{}", stack.join(";"))?;
+    }
+
+    Ok(())
+}
+
+/// The `sample` subcommand: `sample -p PID [--period MILLISECONDS] [--duration SECONDS]
+/// [--call-stacks] [--every-thread] [--json]`, which writes the samples to stdout
+pub fn main(args: &[String]) -> ! {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: sample -p|--pid PID [--period MILLISECONDS] [--duration SECONDS] [--call-stacks] [--every-thread] [--json]"
+        );
+        std::process::exit(2);
+    };
+
+    let mut pid = None;
+    let mut options = Options::default();
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--call-stacks" => options.call_stacks = true,
+            "--every-thread" => options.every_thread = true,
+            "--json" => json = true,
+            "-p" | "--pid" => match args.next().map(|value| value.parse::<i32>()) {
+                Some(Ok(number)) if number > 0 => pid = Some(number),
+                _ => usage(),
+            },
+            "--period" | "--duration" => {
+                let value = args.next().unwrap_or_else(|| usage());
+                let number: f64 = value.parse().unwrap_or_else(|_| usage());
+                // infinite periods and durations do not convert
+                let seconds =
+                    |number: f64| Duration::try_from_secs_f64(number).unwrap_or_else(|_| usage());
+                match arg.as_str() {
+                    "--period" if number > 0.0 && number.is_finite() => {
+                        options.period = seconds(number / 1000.0)
+                    }
+                    "--duration" if number > 0.0 && number.is_finite() => {
+                        options.duration = Some(seconds(number))
+                    }
+                    _ => usage(),
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    let Some(pid) = pid else { usage() };
+
+    interval::catch_interrupt();
+
+    let samples = match sample(pid, &options) {
+        Ok(samples) => samples,
+        Err(e) => {
+            eprintln!("Failed to sample process {pid}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let result = if json {
+        serde_json::to_writer_pretty(&mut stdout, &samples)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(stdout))
+    } else {
+        write_csv(&mut stdout, &samples)
+    };
+
+    if let Err(e) = result {
+        eprintln!("Failed to write the samples: {e}");
+        std::process::exit(1);
+    }
+
+    eprintln!("{} samples of process {pid}", samples.len());
+    std::process::exit(0);
+}
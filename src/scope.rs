@@ -0,0 +1,74 @@
+use std::sync::Mutex;
+
+use crate::{Aggregate, EventCollector, Run};
+
+/// All regions measured so far, in the order in which they were first seen
+static REGIONS: Mutex<Vec<(&'static str, Aggregate)>> = Mutex::new(Vec::new());
+
+/// Guard that measures a named region of code, from its creation until it is dropped.
+/// The measurements are accumulated per name, see `report` and `print_report`.
+pub struct Scope<'a> {
+    collector: &'a mut EventCollector,
+    name: &'static str,
+}
+
+impl EventCollector {
+    /// Start measuring a region; the measurement ends when the returned guard is dropped
+    #[inline(always)]
+    pub fn scope(&mut self, name: &'static str) -> Scope<'_> {
+        self.start();
+
+        Scope {
+            collector: self,
+            name,
+        }
+    }
+}
+
+impl Drop for Scope<'_> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let count = self.collector.end();
+
+        let mut regions = REGIONS.lock().unwrap_or_else(|e| e.into_inner());
+        match regions.iter_mut().find(|(name, _)| *name == self.name) {
+            Some((_, aggregate)) => aggregate.push(count),
+            None => {
+                let mut aggregate = Aggregate::default();
+                aggregate.push(count);
+                regions.push((self.name, aggregate));
+            }
+        }
+    }
+}
+
+/// The accumulated measurements of one named region
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub calls: usize,
+    pub elapsed: core::time::Duration,
+    pub run: Run,
+}
+
+/// The measurements of all regions so far
+pub fn report() -> Vec<Region> {
+    let regions = REGIONS.lock().unwrap_or_else(|e| e.into_inner());
+
+    regions
+        .iter()
+        .map(|(name, aggregate)| Region {
+            name,
+            calls: aggregate.count(),
+            elapsed: aggregate.elapsed(),
+            run: aggregate.run(),
+        })
+        .collect()
+}
+
+/// Print the measurements of all regions so far, typically at the end of `main`
+pub fn print_report() {
+    for region in report() {
+        eprintln!("{:#?}", region);
+    }
+}
@@ -0,0 +1,228 @@
+//! One counter session for the whole process, shared by all threads.
+//!
+//! The performance counters are configured once per process (on macOS, the PMU configuration is
+//! even shared by the whole system), so there is a single `Session` that is set up on first use.
+//! It is `Send` and `Sync`. Every thread reads its own counters through a `Reader`, which is cheap
+//! to create and stays on the thread that created it.
+//!
+//! On macOS the session programs the PMU when it is set up. Everything in this crate that
+//! reprograms the PMU, including an `EventCollector` and kperf sampling, holds a process-wide lock
+//! while it does, so that two of them never interleave their configuration. While raw events
+//! (`raw`, `multiplex` and `passes`) have the PMU, the readers of the session fail, and the first
+//! read after they gave it back fails too, since a delta with a read from before would mix events.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::{CounterValues, EventCount, PerformanceCounters};
+
+/// The process-wide counter session, see `Session::global`
+pub struct Session {
+    #[cfg(not(target_os = "linux"))]
+    collector: crate::EventCollector,
+}
+
+// the session is shared by all threads through a `static`, and owns a collector
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Session>();
+    assert_send_sync::<crate::EventCollector>();
+};
+
+/// The session, or why it could not be set up
+static SESSION: OnceLock<Result<Session, (std::io::ErrorKind, String)>> = OnceLock::new();
+
+impl Session {
+    /// The session of this process, which is set up by the first call
+    pub fn global() -> std::io::Result<&'static Session> {
+        match SESSION.get_or_init(|| Self::new().map_err(|e| (e.kind(), e.to_string()))) {
+            Ok(session) => Ok(session),
+            Err((kind, message)) => Err(std::io::Error::new(*kind, message.clone())),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn new() -> std::io::Result<Self> {
+        // fail here rather than in every reader when there are no counters
+        Reader::open_counters()?;
+        Ok(Self {})
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new() -> std::io::Result<Self> {
+        use libloading::Library;
+
+        let load = |path: &str| {
+            unsafe { Library::new(path) }.map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Error loading {path}: {e}"),
+                )
+            })
+        };
+
+        let mut collector = crate::EventCollector::new(
+            load(crate::LIB_PATH_KPERF)?,
+            load(crate::LIB_PATH_KPERFDATA)?,
+        );
+        if !collector.has_events() {
+            return Err(std::io::Error::other(
+                "performance counters are not available",
+            ));
+        }
+
+        Ok(Self { collector })
+    }
+
+    /// The collector that set up the counters, for the kperf functions
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn collector(&self) -> &crate::EventCollector {
+        &self.collector
+    }
+
+    /// The name of the CPU according to the PMC database; Linux has none
+    pub fn cpu(&self) -> Option<&str> {
+        #[cfg(target_os = "linux")]
+        {
+            None
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.collector.cpu()
+        }
+    }
+
+    /// A reader of the counters of the calling thread
+    pub fn reader(&'static self) -> std::io::Result<Reader> {
+        Ok(Reader {
+            #[cfg(target_os = "linux")]
+            counters: Reader::open_counters()?,
+            #[cfg(not(target_os = "linux"))]
+            session: self,
+            #[cfg(not(target_os = "linux"))]
+            raw: [0; crate::KPC_MAX_COUNTERS],
+            #[cfg(not(target_os = "linux"))]
+            generation: crate::pmu_generation()?,
+            _thread: PhantomData,
+        })
+    }
+}
+
+/// Reads the counters of the thread that created it, so it cannot be sent to other threads
+pub struct Reader {
+    #[cfg(target_os = "linux")]
+    counters: crate::linux::PerfCounters,
+
+    #[cfg(not(target_os = "linux"))]
+    session: &'static Session,
+    #[cfg(not(target_os = "linux"))]
+    raw: [u64; crate::KPC_MAX_COUNTERS],
+    /// The generation of the PMU configuration at the last read
+    #[cfg(not(target_os = "linux"))]
+    generation: u64,
+
+    _thread: PhantomData<*const ()>,
+}
+
+impl Reader {
+    #[cfg(target_os = "linux")]
+    fn open_counters() -> std::io::Result<crate::linux::PerfCounters> {
+        use crate::linux::{PerfCounters, Target};
+
+        let counters = PerfCounters::open(Target {
+            pid: 0,
+            cpu: -1,
+            inherit: false,
+            enable_on_exec: false,
+        })?;
+        counters.enable()?;
+
+        Ok(counters)
+    }
+
+    /// The current value of the counters of this thread
+    #[inline(always)]
+    pub fn read(&mut self) -> std::io::Result<PerformanceCounters> {
+        Ok(self.read_values()?.to_counters())
+    }
+
+    /// The raw values of the counters of this thread, for exact deltas with `CounterValues::delta`
+    #[inline(always)]
+    pub fn read_values(&mut self) -> std::io::Result<CounterValues> {
+        #[cfg(target_os = "linux")]
+        {
+            self.counters.read_values()
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let collector = &self.session.collector;
+            let generation = crate::pmu_generation()?;
+            let ret = unsafe {
+                (collector.kperf_symbols.kpc_get_thread_counters)(
+                    0,
+                    self.raw.len() as u32,
+                    self.raw.as_mut_ptr(),
+                )
+            };
+
+            // the counters of the session went on counting other events in between
+            let previous = std::mem::replace(&mut self.generation, generation);
+            if previous != generation || crate::pmu_generation()? != generation {
+                return Err(std::io::Error::other(
+                    "the counters were reprogrammed with raw events since the last read",
+                ));
+            }
+
+            match ret {
+                0 => Ok(collector.apple_events.values_from_raw(&self.raw)),
+                ret => Err(std::io::Error::other(format!(
+                    "Failed to get thread counters: {ret}"
+                ))),
+            }
+        }
+    }
+
+    /// Run `f`, and count the events of this thread while it runs
+    pub fn count<R>(&mut self, f: impl FnOnce() -> R) -> std::io::Result<(R, EventCount)> {
+        let start_clock = Instant::now();
+        let start = self.read_values()?;
+
+        let value = f();
+
+        let end = self.read_values()?;
+        let elapsed = start_clock.elapsed();
+
+        Ok((value, EventCount::from_values(end.delta(&start)?, elapsed)))
+    }
+}
+
+thread_local! {
+    static THREAD_READER: RefCell<Option<Reader>> = const { RefCell::new(None) };
+}
+
+/// Read the counters of the calling thread with a reader of the global session, which is created
+/// on the first call on every thread
+pub fn read_current_thread() -> std::io::Result<PerformanceCounters> {
+    THREAD_READER.with_borrow_mut(|reader| {
+        let reader = match reader {
+            Some(reader) => reader,
+            None => reader.insert(Session::global()?.reader()?),
+        };
+        reader.read()
+    })
+}
+
+/// Like `read_current_thread`, with the raw values of the counters
+pub fn read_current_thread_values() -> std::io::Result<CounterValues> {
+    THREAD_READER.with_borrow_mut(|reader| {
+        let reader = match reader {
+            Some(reader) => reader,
+            None => reader.insert(Session::global()?.reader()?),
+        };
+        reader.read_values()
+    })
+}
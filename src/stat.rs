@@ -0,0 +1,298 @@
+//! Counting the events of a whole child process, like `perf stat -- command`.
+//!
+//! On Linux the counters are opened for the child before it calls `exec`, and are enabled by the
+//! `exec`, so that only the command itself is counted. With `inherit` the threads and processes
+//! that it creates are counted too.
+//!
+//! On macOS kpc can only count the current thread or whole CPUs, so the child is measured with
+//! the counters of all CPUs while it runs. This includes everything else that runs at the same
+//! time, and always includes the threads and processes of the child.
+
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::table::{self, Style};
+use crate::{EventCount, PerformanceCounters, Run};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub repeat: usize,
+    /// Also count the threads and child processes of the command
+    pub inherit: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            repeat: 1,
+            inherit: true,
+        }
+    }
+}
+
+/// Run the command `repeat` times, and return the events counted for every run together with
+/// the exit status of the last run
+pub fn stat(
+    command: &[String],
+    options: &Options,
+) -> std::io::Result<(Vec<EventCount>, ExitStatus)> {
+    if command.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no command given",
+        ));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let mut collector = crate::EventCollector::load();
+
+    let mut samples = Vec::with_capacity(options.repeat);
+    let mut status = ExitStatus::default();
+
+    for _ in 0..options.repeat {
+        #[cfg(target_os = "linux")]
+        let (counters, elapsed, exit_status) = run_linux(command, options.inherit)?;
+
+        #[cfg(not(target_os = "linux"))]
+        let (counters, elapsed, exit_status) = run_all_cpus(&mut collector, command)?;
+
+        samples.push(EventCount::from_counters(counters, elapsed));
+        status = exit_status;
+    }
+
+    Ok((samples, status))
+}
+
+#[cfg(target_os = "linux")]
+fn run_linux(
+    command: &[String],
+    inherit: bool,
+) -> std::io::Result<(PerformanceCounters, Duration, ExitStatus)> {
+    let child = CountedChild::spawn(command, inherit)?;
+    let status = child.wait(true)?.unwrap_or_default();
+    let elapsed = child.start.elapsed();
+
+    Ok((child.counters.read()?, elapsed, status))
+}
+
+/// A child process that runs a command, with counters that were opened before it called `exec`
+#[cfg(target_os = "linux")]
+pub(crate) struct CountedChild {
+    pub pid: i32,
+    pub counters: crate::linux::PerfCounters,
+    /// When the child was allowed to call `exec`
+    pub start: std::time::Instant,
+}
+
+#[cfg(target_os = "linux")]
+impl CountedChild {
+    pub fn spawn(command: &[String], inherit: bool) -> std::io::Result<Self> {
+        use std::ffi::CString;
+
+        use crate::linux::{PerfCounters, Target};
+
+        if command.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no command given",
+            ));
+        }
+
+        let to_cstring = |arg: &String| {
+            CString::new(arg.as_str())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        };
+        let args: Vec<CString> = command.iter().map(to_cstring).collect::<Result<_, _>>()?;
+        let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(core::ptr::null());
+
+        // the child waits on this pipe until its counters are opened
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let [read_end, write_end] = pipe;
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            let error = std::io::Error::last_os_error();
+            unsafe {
+                libc::close(read_end);
+                libc::close(write_end);
+            }
+            return Err(error);
+        }
+
+        if pid == 0 {
+            // only async-signal-safe functions from here on
+            unsafe {
+                libc::close(write_end);
+
+                let mut go = 0u8;
+                if libc::read(read_end, (&mut go as *mut u8).cast(), 1) == 1 {
+                    libc::execvp(argv[0], argv.as_ptr());
+                }
+
+                libc::_exit(127);
+            }
+        }
+
+        unsafe { libc::close(read_end) };
+
+        let counters = PerfCounters::open(Target {
+            pid,
+            cpu: -1,
+            inherit,
+            enable_on_exec: true,
+        });
+
+        // closing the pipe without writing makes the child exit without running the command
+        let start = std::time::Instant::now();
+        if counters.is_ok() {
+            unsafe { libc::write(write_end, [1u8].as_ptr().cast(), 1) };
+        }
+        unsafe { libc::close(write_end) };
+
+        match counters {
+            Ok(counters) => Ok(Self {
+                pid,
+                counters,
+                start,
+            }),
+            Err(e) => {
+                unsafe { libc::waitpid(pid, core::ptr::null_mut(), 0) };
+                Err(e)
+            }
+        }
+    }
+
+    /// The exit status of the child, or `None` if it is still running and `block` is false
+    pub fn wait(&self, block: bool) -> std::io::Result<Option<ExitStatus>> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let options = if block { 0 } else { libc::WNOHANG };
+        let mut status = 0;
+        match unsafe { libc::waitpid(self.pid, &mut status, options) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            0 => Ok(None),
+            _ => Ok(Some(ExitStatus::from_raw(status))),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_all_cpus(
+    collector: &mut crate::EventCollector,
+    command: &[String],
+) -> std::io::Result<(PerformanceCounters, Duration, ExitStatus)> {
+    let start = std::time::Instant::now();
+    let before = collector.read_cpu_values()?;
+
+    let status = std::process::Command::new(&command[0])
+        .args(&command[1..])
+        .status()?;
+
+    let after = collector.read_cpu_values()?;
+    let elapsed = start.elapsed();
+
+    Ok((crate::cpus_delta(&before, &after)?, elapsed, status))
+}
+
+/// The `stat` subcommand: `stat [-r N] [--no-inherit] [-m MODIFIERS] [-I MILLISECONDS [--json]]
+/// [--] command [args...]`. The modifiers are the modes to count in, such as `u` (see `modes`).
+/// With an interval, the counters of every interval are written to stdout as CSV or JSON.
+pub fn main(args: &[String]) -> ! {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: stat [-r|--repeat N] [--no-inherit] [-m|--modes u|k|uk] [-I|--interval MILLISECONDS [--json]] [--] command [args...]"
+        );
+        std::process::exit(2);
+    };
+
+    let mut options = Options::default();
+    let mut interval = None;
+    let mut json = false;
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with('-')) {
+        match arg.as_str() {
+            "--" => break,
+            "-r" | "--repeat" => match args.next().map(|value| value.parse()) {
+                Some(Ok(repeat)) if repeat > 0 => options.repeat = repeat,
+                _ => usage(),
+            },
+            "--no-inherit" => options.inherit = false,
+            "-m" | "--modes" => match args.next().map(|value| value.parse()) {
+                Some(Ok(modes)) => crate::modes::set_modes(modes).unwrap_or_else(|_| usage()),
+                _ => usage(),
+            },
+            "-I" | "--interval" => match args.next().map(|value| value.parse()) {
+                Some(Ok(milliseconds)) if milliseconds > 0 => {
+                    interval = Some(Duration::from_millis(milliseconds))
+                }
+                _ => usage(),
+            },
+            "--json" => json = true,
+            _ => usage(),
+        }
+    }
+
+    let command: Vec<String> = args.cloned().collect();
+    if command.is_empty() || (interval.is_some() && options.repeat > 1) {
+        usage();
+    }
+
+    if let Some(interval) = interval {
+        interval_main(&command, interval, options.inherit, json);
+    }
+
+    let (samples, status) = match stat(&command, &options) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Failed to count events of {}: {e}", command[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let run = Run::from_samples(&samples);
+    let name = command.join(" ");
+    eprintln!();
+    eprint!(
+        "{}",
+        table::format_run(&name, samples.len(), &run, Style::for_stderr())
+    );
+
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn interval_main(command: &[String], interval: Duration, inherit: bool, json: bool) -> ! {
+    use crate::timeseries;
+
+    let (series, status) = match timeseries::record_command(interval, command, inherit) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Failed to count events of {}: {e}", command[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let written = if json {
+        series.write_json(&mut stdout)
+    } else {
+        series.write_csv(&mut stdout)
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write the intervals: {e}");
+        std::process::exit(1);
+    }
+
+    let elapsed = Duration::from_nanos(series.points.last().map_or(0, |point| point.time_ns));
+    let run = Run::from_samples(&[EventCount::from_counters(series.total(), elapsed)]);
+    eprintln!();
+    eprint!(
+        "{}",
+        table::format_run(&command.join(" "), 1, &run, Style::for_stderr())
+    );
+
+    std::process::exit(status.code().unwrap_or(1));
+}
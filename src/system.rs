@@ -0,0 +1,291 @@
+//! Counting everything that runs on a set of CPUs, like `perf stat -a`, for finding out what else
+//! runs on a benchmark machine.
+//!
+//! On macOS these are the per-CPU counters of kpc, which count all CPUs at once, so selecting CPUs
+//! only selects what is reported. On Linux a perf event is opened for every selected CPU, which
+//! needs `CAP_PERFMON` or a `perf_event_paranoid` of 0 or less.
+
+use std::time::{Duration, Instant};
+
+use crate::table::{format_counters, Style};
+use crate::PerformanceCounters;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// The CPUs to count, all CPUs if `None`
+    pub cpus: Option<Vec<usize>>,
+    /// Stop after this long; otherwise count until Ctrl-C
+    pub duration: Option<Duration>,
+    /// Report the counters of every interval of this length while counting
+    pub interval: Option<Duration>,
+}
+
+/// The events counted on every selected CPU over some time
+#[derive(Debug, Clone, Default)]
+pub struct CpuCounts {
+    pub elapsed: Duration,
+    /// The index of every CPU, with its counters
+    pub cpus: Vec<(usize, PerformanceCounters)>,
+}
+
+impl CpuCounts {
+    pub fn total(&self) -> PerformanceCounters {
+        let mut total = PerformanceCounters::default();
+        for (_, counters) in self.cpus.iter() {
+            total += *counters;
+        }
+        total
+    }
+
+    fn delta(&self, previous: &CpuCounts) -> CpuCounts {
+        let cpus = self
+            .cpus
+            .iter()
+            .zip(previous.cpus.iter())
+            .map(|(&(cpu, current), &(_, previous))| (cpu, current - previous))
+            .collect();
+
+        CpuCounts {
+            elapsed: self.elapsed.saturating_sub(previous.elapsed),
+            cpus,
+        }
+    }
+}
+
+/// The counters of a set of CPUs, which count from when they are opened
+pub struct SystemCounters {
+    cpus: Vec<usize>,
+    start: Instant,
+    #[cfg(target_os = "linux")]
+    counters: Vec<crate::linux::PerfCounters>,
+    #[cfg(not(target_os = "linux"))]
+    collector: crate::EventCollector,
+    /// The counters of all CPUs when opened, since kpc counts all the time
+    #[cfg(not(target_os = "linux"))]
+    baseline: Vec<crate::CounterValues>,
+}
+
+impl SystemCounters {
+    /// Open the counters of the given CPUs, or of all CPUs
+    pub fn open(cpus: Option<&[usize]>) -> std::io::Result<Self> {
+        let cpu_count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as usize;
+
+        let cpus = match cpus {
+            Some(cpus) => {
+                if let Some(cpu) = cpus.iter().find(|&&cpu| cpu >= cpu_count) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("there is no CPU {cpu}, this machine has {cpu_count}"),
+                    ));
+                }
+                cpus.to_vec()
+            }
+            None => (0..cpu_count).collect(),
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            use crate::linux::{PerfCounters, Target};
+
+            let mut counters = Vec::with_capacity(cpus.len());
+            for &cpu in cpus.iter() {
+                let target = Target {
+                    pid: -1,
+                    cpu: cpu as i32,
+                    inherit: false,
+                    enable_on_exec: false,
+                };
+                counters.push(PerfCounters::open(target)?);
+            }
+
+            let start = Instant::now();
+            for cpu in counters.iter() {
+                cpu.enable()?;
+            }
+
+            Ok(Self {
+                cpus,
+                start,
+                counters,
+            })
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut collector = crate::EventCollector::load();
+            let start = Instant::now();
+            let baseline = collector.read_cpu_values()?;
+
+            Ok(Self {
+                cpus,
+                start,
+                collector,
+                baseline,
+            })
+        }
+    }
+
+    /// The events counted on every CPU since the counters were opened
+    pub fn read(&mut self) -> std::io::Result<CpuCounts> {
+        let elapsed = self.start.elapsed();
+
+        #[cfg(target_os = "linux")]
+        let cpus = self
+            .cpus
+            .iter()
+            .zip(self.counters.iter())
+            .map(|(&cpu, counters)| Ok((cpu, counters.read()?)))
+            .collect::<std::io::Result<_>>()?;
+
+        #[cfg(not(target_os = "linux"))]
+        let cpus = {
+            let all = self.collector.read_cpu_values()?;
+            let counters = |cpu: usize| {
+                let current = all.get(cpu).copied().unwrap_or_default();
+                let baseline = self.baseline.get(cpu).copied().unwrap_or_default();
+                Ok((cpu, current.delta(&baseline)?.to_counters()))
+            };
+            self.cpus
+                .iter()
+                .map(|&cpu| counters(cpu))
+                .collect::<std::io::Result<_>>()?
+        };
+
+        Ok(CpuCounts { elapsed, cpus })
+    }
+}
+
+/// Count the events of the selected CPUs. With an interval, `on_interval` is called with the time
+/// since the start and the counts of every interval.
+pub fn count(
+    options: &Options,
+    mut on_interval: impl FnMut(Duration, &CpuCounts),
+) -> std::io::Result<CpuCounts> {
+    let mut counters = SystemCounters::open(options.cpus.as_deref())?;
+    let first = counters.read()?;
+    let mut previous = first.clone();
+
+    crate::interval::run(
+        counters.start,
+        options.interval,
+        options.duration,
+        || false,
+        |time| {
+            let current = counters.read()?;
+            on_interval(time, &current.delta(&previous));
+            previous = current;
+            Ok(())
+        },
+    )?;
+
+    Ok(counters.read()?.delta(&first))
+}
+
+/// Parse a list of CPUs like `0,2-3`
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for part in list.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+fn print_counts(prefix: &str, counts: &CpuCounts, per_cpu: bool) {
+    let style = Style::default();
+
+    if per_cpu {
+        for (cpu, counters) in counts.cpus.iter() {
+            let cpu = format!("CPU{cpu}");
+            eprintln!("{prefix}{cpu:>7}{}", format_counters(counters, style));
+        }
+    }
+    eprintln!(
+        "{prefix}{:>7}{}",
+        "total",
+        format_counters(&counts.total(), style)
+    );
+}
+
+/// The `system` subcommand: `system [-C|--cpus LIST] [--per-cpu] [-m MODIFIERS] [--duration
+/// SECONDS] [--interval MILLISECONDS]`, where the modifiers are the modes to count in, such as `k`
+/// (see `modes`)
+pub fn main(args: &[String]) -> ! {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: system [-C|--cpus 0,2-3] [--per-cpu] [-m|--modes u|k|uk] [--duration SECONDS] [--interval MILLISECONDS]"
+        );
+        std::process::exit(2);
+    };
+
+    let mut options = Options::default();
+    let mut per_cpu = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--per-cpu" {
+            per_cpu = true;
+            continue;
+        }
+
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-C" | "--cpus" => {
+                options.cpus = Some(parse_cpu_list(value).unwrap_or_else(|| usage()))
+            }
+            "-m" | "--modes" => crate::modes::set_modes(value.parse().unwrap_or_else(|_| usage()))
+                .unwrap_or_else(|_| usage()),
+            "--duration" => {
+                let seconds: f64 = value.parse().unwrap_or_else(|_| usage());
+                // negative, infinite and NaN durations do not convert
+                let duration = Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage());
+                if duration.is_zero() {
+                    usage();
+                }
+                options.duration = Some(duration);
+            }
+            "--interval" => match value.parse() {
+                Ok(milliseconds) if milliseconds > 0 => {
+                    options.interval = Some(Duration::from_millis(milliseconds))
+                }
+                _ => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    crate::interval::catch_interrupt();
+
+    let on_interval = |time: Duration, counts: &CpuCounts| {
+        print_counts(&format!("{:>10.3}s", time.as_secs_f64()), counts, per_cpu);
+    };
+
+    match count(&options, on_interval) {
+        Ok(counts) => {
+            eprintln!();
+            eprintln!(
+                " Performance counter stats for {} CPUs ({:.3}s):",
+                counts.cpus.len(),
+                counts.elapsed.as_secs_f64()
+            );
+            print_counts(&format!("{:>11}", ""), &counts, per_cpu);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Failed to count events system-wide: {e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Human-readable output of measurements, in the style of `perf stat`.
+
+use std::fmt::Write;
+
+use crate::{Counter, PerformanceCounters, Run};
+
+/// How `format_run` lays out its table
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Style {
+    /// Highlight the names and colour the standard deviation by how noisy the counter is
+    pub colour: bool,
+    /// A Markdown table, for pasting into pull requests
+    pub markdown: bool,
+    /// Scale large values to K/M/G/T instead of printing every digit
+    pub scale: bool,
+}
+
+impl Style {
+    /// Colour when stdout is a terminal and `NO_COLOR` is not set
+    pub fn for_stdout() -> Self {
+        use std::io::IsTerminal;
+
+        Self {
+            colour: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            ..Self::default()
+        }
+    }
+
+    /// Colour when stderr is a terminal and `NO_COLOR` is not set
+    pub fn for_stderr() -> Self {
+        use std::io::IsTerminal;
+
+        Self {
+            colour: std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            ..Self::default()
+        }
+    }
+}
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// The relative standard deviations in percent below which a counter is steady and somewhat
+/// noisy. Instructions of a deterministic benchmark stay far below 1%; cycles of a benchmark of a
+/// few milliseconds are usually between 0.3% and 2%, and above 5% something else ran on the CPU.
+const STEADY: f64 = 1.0;
+const NOISY: f64 = 5.0;
+
+/// Format the aggregates of a run as a table with a row per counter, followed by derived ratios
+pub fn format_run(name: &str, repeat: usize, run: &Run, style: Style) -> String {
+    let mut out = String::new();
+
+    // writing to a `String` cannot fail
+    let _ = if style.markdown {
+        write_markdown(&mut out, name, repeat, run, style)
+    } else {
+        write_plain(&mut out, name, repeat, run, style)
+    };
+
+    out
+}
+
+fn write_plain(
+    out: &mut String,
+    name: &str,
+    repeat: usize,
+    run: &Run,
+    style: Style,
+) -> std::fmt::Result {
+    let (bold, reset) = if style.colour {
+        (BOLD, RESET)
+    } else {
+        ("", "")
+    };
+
+    writeln!(
+        out,
+        " Performance counter stats for '{bold}{name}{reset}' ({repeat} runs):"
+    )?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:>16} {:>18} {:>10} {:>18} {:>18}",
+        "counter", "mean", "± stddev", "min", "max"
+    )?;
+
+    for counter in Counter::ALL {
+        let mean = counter.get(&run.mean);
+        let deviation = relative_deviation(counter, run);
+        let deviation_colour = match (style.colour, deviation) {
+            (false, _) => "",
+            (true, d) if d < STEADY => GREEN,
+            (true, d) if d < NOISY => YELLOW,
+            (true, _) => RED,
+        };
+        let deviation_reset = if style.colour { RESET } else { "" };
+
+        writeln!(
+            out,
+            "{:>16} {:>18} {deviation_colour}{:>10}{deviation_reset} {:>18} {:>18}",
+            counter.name(),
+            format_value(mean, style),
+            format!("{deviation:.2}%"),
+            format_value(counter.get(&run.minimum), style),
+            format_value(counter.get(&run.maximum), style),
+        )?;
+    }
+
+    writeln!(out)?;
+    for (value, description) in ratios(&run.mean) {
+        writeln!(out, "{value:>16} {description}")?;
+    }
+
+    Ok(())
+}
+
+fn write_markdown(
+    out: &mut String,
+    name: &str,
+    repeat: usize,
+    run: &Run,
+    style: Style,
+) -> std::fmt::Result {
+    writeln!(out, "**{name}** ({repeat} runs)")?;
+    writeln!(out)?;
+    writeln!(out, "| counter | mean | ± stddev | min | max |")?;
+    writeln!(out, "|:--|--:|--:|--:|--:|")?;
+
+    for counter in Counter::ALL {
+        writeln!(
+            out,
+            "| {} | {} | {:.2}% | {} | {} |",
+            counter.name(),
+            format_value(counter.get(&run.mean), style),
+            relative_deviation(counter, run),
+            format_value(counter.get(&run.minimum), style),
+            format_value(counter.get(&run.maximum), style),
+        )?;
+    }
+
+    writeln!(out)?;
+    for (value, description) in ratios(&run.mean) {
+        writeln!(out, "- {value} {description}")?;
+    }
+
+    Ok(())
+}
+
+/// The standard deviation as a percentage of the mean
+fn relative_deviation(counter: Counter, run: &Run) -> f64 {
+    let mean = counter.get(&run.mean);
+    if mean == 0.0 {
+        0.0
+    } else {
+        100.0 * counter.get(&run.standard_deviation) / mean
+    }
+}
+
+/// Ratios between counters that are more meaningful than the counters themselves
+fn ratios(counters: &PerformanceCounters) -> Vec<(String, &'static str)> {
+    let mut ratios = Vec::new();
+
+    if counters.cycles > 0.0 {
+        let ipc = counters.instructions / counters.cycles;
+        ratios.push((format!("{ipc:.2}"), "instructions per cycle"));
+    }
+
+    if counters.branches > 0.0 {
+        let miss_rate = 100.0 * counters.missed_branches / counters.branches;
+        ratios.push((format!("{miss_rate:.2}%"), "of all branches missed"));
+    }
+
+    ratios
+}
+
+/// Format counters on a single line, for printing one line per interval or per CPU
+pub fn format_counters(counters: &PerformanceCounters, style: Style) -> String {
+    let mut out = String::new();
+
+    for counter in Counter::ALL {
+        let value = format_value(counter.get(counters), style);
+        let _ = write!(out, " {value:>18} {}", counter.name());
+    }
+
+    out
+}
+
+/// Format a value with thousands separators, or scaled to a unit if the style asks for that
+pub fn format_value(value: f64, style: Style) -> String {
+    if style.scale {
+        for (factor, unit) in [(1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "K")] {
+            if value.abs() >= factor {
+                return format!("{:.2} {unit}", value / factor);
+            }
+        }
+    }
+
+    let formatted = if value.fract() == 0.0 {
+        format!("{value:.0}")
+    } else {
+        format!("{value:.1}")
+    };
+
+    let (sign, formatted) = match formatted.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", formatted.as_str()),
+    };
+
+    let (integer, fraction) = match formatted.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (formatted, None),
+    };
+
+    let mut out = String::from(sign);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+
+    if let Some(fraction) = fraction {
+        out.push('.');
+        out.push_str(fraction);
+    }
+
+    out
+}
+
+impl std::fmt::Display for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for counter in Counter::ALL {
+            writeln!(
+                f,
+                "{:>16} {:>18} ± {:.2}%",
+                counter.name(),
+                format_value(counter.get(&self.mean), Style::default()),
+                relative_deviation(counter, self),
+            )?;
+        }
+
+        for (value, description) in ratios(&self.mean) {
+            writeln!(f, "{value:>16} {description}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventCount;
+
+    #[test]
+    fn a_steady_run_is_green() {
+        // 100 samples that are 0.5% above or below the mean
+        let samples: Vec<_> = (0..100)
+            .map(|i| {
+                let cycles = if i % 2 == 0 { 995_000 } else { 1_005_000 };
+                EventCount::from_counters(
+                    PerformanceCounters::new_u64(cycles, 0, 0, 0),
+                    std::time::Duration::ZERO,
+                )
+            })
+            .collect();
+        let run = Run::from_samples(&samples);
+
+        let deviation = relative_deviation(Counter::Cycles, &run);
+        assert!((deviation - 0.5).abs() < 1e-9, "{deviation}");
+
+        let style = Style {
+            colour: true,
+            ..Style::default()
+        };
+        let table = format_run("steady", samples.len(), &run, style);
+        let cycles = table.lines().find(|line| line.contains("cycles")).unwrap();
+        assert!(cycles.contains(GREEN), "{cycles:?}");
+    }
+}
@@ -0,0 +1,242 @@
+//! Counting a closure together with the threads that it spawns.
+//!
+//! On Linux the counters are inherited by every thread that the closure creates, so all of them
+//! are counted without doing anything in the threads. The threads that exit before the closure
+//! returns are also reported one by one; the ones that are still running, like the threads of a
+//! global thread pool, are only in the total.
+//!
+//! On macOS every thread can only read its own counters, so threads have to call
+//! `register_thread` when they start (or be started with `spawn`), and report their counts when
+//! they exit. With rayon, `ThreadPoolBuilder::start_handler` can do that. Threads that have not
+//! exited when the closure returns are not counted, and a thread whose counters could not be read
+//! makes `count_threads` fail rather than count it as zero events.
+
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::table::{format_counters, Style};
+use crate::PerformanceCounters;
+
+/// The events counted on one thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadCount {
+    /// The id of the thread in the OS
+    pub thread: u64,
+    pub counters: PerformanceCounters,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThreadCounts {
+    pub elapsed: Duration,
+    /// The calling thread first, then every thread that exited, in the order in which they exited
+    pub threads: Vec<ThreadCount>,
+    /// Threads that were still running when the closure returned, which cannot be told apart
+    pub running: PerformanceCounters,
+    pub total: PerformanceCounters,
+}
+
+/// The threads that reported while `count_threads` runs, or why they could not count
+static REPORTS: Mutex<Option<Vec<std::io::Result<ThreadCount>>>> = Mutex::new(None);
+
+fn reports() -> MutexGuard<'static, Option<Vec<std::io::Result<ThreadCount>>>> {
+    REPORTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Stops collecting reports when dropped, also when `f` panics
+struct Collecting;
+
+impl Drop for Collecting {
+    fn drop(&mut self) {
+        *reports() = None;
+    }
+}
+
+/// Run `f`, and count the events of the calling thread and of every thread that `f` spawns
+pub fn count_threads<R>(f: impl FnOnce() -> R) -> std::io::Result<(R, ThreadCounts)> {
+    {
+        let mut reports = reports();
+        if reports.is_some() {
+            return Err(std::io::Error::other("already counting threads"));
+        }
+        *reports = Some(Vec::new());
+    }
+    let collecting = Collecting;
+
+    let result = count(f);
+    let reports = reports().take().unwrap_or_default();
+    drop(collecting);
+
+    let (value, mut counts) = result?;
+    for report in reports {
+        counts.threads.push(report?);
+    }
+
+    // the threads report themselves, so only the sum of the reports is known
+    #[cfg(not(target_os = "linux"))]
+    for thread in counts.threads.iter() {
+        counts.total += thread.counters;
+    }
+
+    Ok((value, counts))
+}
+
+#[cfg(target_os = "linux")]
+fn count<R>(f: impl FnOnce() -> R) -> std::io::Result<(R, ThreadCounts)> {
+    use crate::linux::{InheritedCounters, PerfCounters, Target};
+    use std::time::Instant;
+
+    let mut all = InheritedCounters::open()?;
+    let this = PerfCounters::open(Target {
+        pid: 0,
+        cpu: -1,
+        inherit: false,
+        enable_on_exec: false,
+    })?;
+
+    let start = Instant::now();
+    all.enable()?;
+    this.enable()?;
+
+    let value = f();
+
+    let this = this.read()?;
+    let total = all.read()?;
+    let elapsed = start.elapsed();
+
+    let mut threads = vec![ThreadCount {
+        thread: current_thread(),
+        counters: this,
+    }];
+    let mut running = total - this;
+    for (tid, counters) in all.exited() {
+        running -= counters;
+        threads.push(ThreadCount {
+            thread: tid as u64,
+            counters,
+        });
+    }
+
+    let counts = ThreadCounts {
+        elapsed,
+        threads,
+        running,
+        total,
+    };
+
+    Ok((value, counts))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn count<R>(f: impl FnOnce() -> R) -> std::io::Result<(R, ThreadCounts)> {
+    let mut reader = crate::session::Session::global()?.reader()?;
+    let (value, this) = reader.count(f)?;
+
+    let counts = ThreadCounts {
+        elapsed: this.elapsed,
+        threads: vec![ThreadCount {
+            thread: current_thread(),
+            counters: PerformanceCounters::from_event_count(this),
+        }],
+        ..ThreadCounts::default()
+    };
+
+    Ok((value, counts))
+}
+
+/// Count the current thread until it exits, for `count_threads` on macOS, with a reader of the
+/// global session. Does nothing on Linux, where threads are counted without this.
+pub fn register_thread() -> std::io::Result<()> {
+    #[cfg(not(target_os = "linux"))]
+    REGISTRATION.with_borrow_mut(|registration| {
+        if registration.is_none() {
+            let mut reader = crate::session::Session::global()?.reader()?;
+            let start = reader.read_values()?;
+            *registration = Some(Registration { reader, start });
+        }
+        Ok::<_, std::io::Error>(())
+    })?;
+
+    Ok(())
+}
+
+/// `std::thread::spawn`, with the new thread registered for `count_threads`. If the thread cannot
+/// be registered, `count_threads` returns the error.
+pub fn spawn<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::spawn(move || {
+        if let Err(e) = register_thread() {
+            report(Err(e));
+        }
+        f()
+    })
+}
+
+/// Add the count of a thread to the reports, if `count_threads` is running
+fn report(count: std::io::Result<ThreadCount>) {
+    if let Some(reports) = reports().as_mut() {
+        reports.push(count);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Registration {
+    reader: crate::session::Reader,
+    start: crate::CounterValues,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let counters = self
+            .reader
+            .read_values()
+            .and_then(|end| end.delta(&self.start));
+
+        report(counters.map(|counters| ThreadCount {
+            thread: current_thread(),
+            counters: counters.to_counters(),
+        }));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+thread_local! {
+    static REGISTRATION: std::cell::RefCell<Option<Registration>> = const { std::cell::RefCell::new(None) };
+}
+
+fn current_thread() -> u64 {
+    #[cfg(target_os = "linux")]
+    {
+        unsafe { libc::gettid() as u64 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let mut thread = 0;
+        unsafe { libc::pthread_threadid_np(0, &mut thread) };
+        thread
+    }
+}
+
+impl std::fmt::Display for ThreadCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let style = Style::default();
+
+        for thread in self.threads.iter() {
+            let name = format!("thread {}", thread.thread);
+            writeln!(f, "{name:>16}{}", format_counters(&thread.counters, style))?;
+        }
+        if self.running != PerformanceCounters::default() {
+            writeln!(
+                f,
+                "{:>16}{}",
+                "running",
+                format_counters(&self.running, style)
+            )?;
+        }
+        writeln!(f, "{:>16}{}", "total", format_counters(&self.total, style))
+    }
+}
@@ -0,0 +1,294 @@
+//! The counters of every interval while a closure or a command runs, for seeing the phases that a
+//! single total hides.
+//!
+//! On Linux the counters of the thread or the child are read every interval from another thread.
+//! The counters of the threads and processes that a command creates only show up once they exit.
+//!
+//! On macOS the counters of a thread can only be read by the thread itself, so a closure is
+//! sampled with a kperf timer (see `sampling`, this needs root) and the samples are summed per
+//! interval. A command is measured with the counters of all CPUs, like in `stat`.
+
+use std::io::Write;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{interval, Counter, CounterValues, PerformanceCounters};
+
+/// The events counted during one interval
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Point {
+    /// Nanoseconds from the start to the end of the interval
+    pub time_ns: u64,
+    pub counters: PerformanceCounters,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimeSeries {
+    pub interval_ns: u64,
+    pub points: Vec<Point>,
+}
+
+impl TimeSeries {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval_ns: interval.as_nanos() as u64,
+            points: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, time: Duration, counters: PerformanceCounters) {
+        self.points.push(Point {
+            time_ns: time.as_nanos() as u64,
+            counters,
+        });
+    }
+
+    pub fn total(&self) -> PerformanceCounters {
+        let mut total = PerformanceCounters::default();
+        for point in self.points.iter() {
+            total += point.counters;
+        }
+        total
+    }
+
+    /// One row per interval, with the instructions per cycle of the interval
+    pub fn write_csv(&self, w: &mut impl Write) -> std::io::Result<()> {
+        write!(w, "time_ns")?;
+        for counter in Counter::ALL {
+            write!(w, ",{}", counter.name())?;
+        }
+        writeln!(w, ",ipc")?;
+
+        for point in self.points.iter() {
+            write!(w, "{}", point.time_ns)?;
+            for counter in Counter::ALL {
+                write!(w, ",{}", counter.get(&point.counters))?;
+            }
+
+            let counters = &point.counters;
+            if counters.cycles > 0.0 {
+                writeln!(w, ",{:.4}", counters.instructions / counters.cycles)?;
+            } else {
+                writeln!(w, ",")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_json(&self, w: &mut impl Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut *w, self)?;
+        writeln!(w)
+    }
+}
+
+/// Run `f` on this thread, and record its counters every `interval`
+pub fn record(interval: Duration, f: impl FnOnce()) -> std::io::Result<TimeSeries> {
+    let interval = interval.max(Duration::from_micros(1));
+    let finished = AtomicBool::new(false);
+
+    #[cfg(target_os = "linux")]
+    {
+        use crate::linux::{PerfCounters, Target};
+
+        let counters = PerfCounters::open(Target {
+            pid: unsafe { libc::gettid() },
+            cpu: -1,
+            inherit: false,
+            enable_on_exec: false,
+        })?;
+
+        let mut series = TimeSeries::new(interval);
+        let mut previous = CounterValues::default();
+
+        let start = Instant::now();
+        counters.enable()?;
+
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                interval::run(
+                    start,
+                    Some(interval),
+                    None,
+                    || finished.load(Ordering::Relaxed),
+                    |time| {
+                        let current = counters.read_values()?;
+                        series.push(time, current.delta(&previous)?.to_counters());
+                        previous = current;
+                        Ok(())
+                    },
+                )
+            });
+
+            f();
+            finished.store(true, Ordering::Relaxed);
+            reader.join().unwrap()
+        })?;
+
+        // the part of the last interval that `f` still ran for
+        let elapsed = start.elapsed();
+        if series.points.last().map(|point| point.time_ns) < Some(elapsed.as_nanos() as u64) {
+            let current = counters.read_values()?;
+            series.push(elapsed, current.delta(&previous)?.to_counters());
+        }
+
+        Ok(series)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        use crate::sampling::{self, KperfSession, TraceDecoder};
+
+        let mut collector = crate::EventCollector::load();
+        if !collector.has_events() {
+            return Err(std::io::Error::other(
+                "performance counters are not available",
+            ));
+        }
+
+        let mut thread = 0;
+        unsafe { libc::pthread_threadid_np(0, &mut thread) };
+
+        let options = sampling::Options {
+            period: interval,
+            ..sampling::Options::default()
+        };
+        let session = KperfSession::start(&collector, std::process::id() as i32, &options)?;
+
+        // the trace has to be read while `f` runs, or it fills up
+        let entries = std::thread::scope(|scope| {
+            let reader = scope.spawn(|| -> std::io::Result<_> {
+                let mut entries = Vec::new();
+                let mut read = Vec::new();
+                loop {
+                    let done = finished.load(Ordering::Relaxed);
+                    crate::kdebug::read(&mut read, KperfSession::BUFFER_ENTRIES)?;
+                    entries.extend_from_slice(&read);
+                    if done {
+                        return Ok(entries);
+                    }
+                    std::thread::sleep(sampling::DRAIN_PERIOD);
+                }
+            });
+
+            f();
+            finished.store(true, Ordering::Relaxed);
+            reader.join().unwrap()
+        })?;
+        drop(session);
+
+        let mut samples = Vec::new();
+        TraceDecoder::default().decode(&collector, &entries, &mut samples)?;
+
+        // sum the samples of this thread per interval
+        let mut series = TimeSeries::new(interval);
+        for sample in samples.iter().filter(|sample| sample.thread == thread) {
+            let end = (sample.time_ns / series.interval_ns + 1) * series.interval_ns;
+            match series.points.last_mut() {
+                Some(point) if point.time_ns == end => point.counters += sample.counters,
+                _ => series.points.push(Point {
+                    time_ns: end,
+                    counters: sample.counters,
+                }),
+            }
+        }
+
+        Ok(series)
+    }
+}
+
+/// Run a command, and record its counters every `interval`
+pub fn record_command(
+    interval: Duration,
+    command: &[String],
+    inherit: bool,
+) -> std::io::Result<(TimeSeries, ExitStatus)> {
+    let interval = interval.max(Duration::from_micros(1));
+    let mut series = TimeSeries::new(interval);
+    let mut status = None;
+
+    #[cfg(target_os = "linux")]
+    {
+        let child = crate::stat::CountedChild::spawn(command, inherit)?;
+        let mut previous = CounterValues::default();
+
+        interval::run(
+            child.start,
+            Some(interval),
+            None,
+            || {
+                // a failing wait is reported by the blocking wait below
+                status = child.wait(false).unwrap_or(None);
+                status.is_some()
+            },
+            |time| {
+                let current = child.counters.read_values()?;
+                series.push(time, current.delta(&previous)?.to_counters());
+                previous = current;
+                Ok(())
+            },
+        )?;
+
+        let status = match status {
+            Some(status) => status,
+            None => child.wait(true)?.unwrap_or_default(),
+        };
+        let current = child.counters.read_values()?;
+        series.push(
+            child.start.elapsed(),
+            current.delta(&previous)?.to_counters(),
+        );
+
+        Ok((series, status))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        // the counters of all CPUs include the threads and processes of the command anyway
+        let _ = inherit;
+
+        if command.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no command given",
+            ));
+        }
+
+        let mut collector = crate::EventCollector::load();
+        let mut read = || collector.read_cpu_values();
+
+        let start = Instant::now();
+        let mut previous = read()?;
+        let mut child = std::process::Command::new(&command[0])
+            .args(&command[1..])
+            .spawn()?;
+
+        interval::run(
+            start,
+            Some(interval),
+            None,
+            || {
+                status = child.try_wait().unwrap_or(None);
+                status.is_some()
+            },
+            |time| {
+                let current = read()?;
+                series.push(time, crate::cpus_delta(&previous, &current)?);
+                previous = current;
+                Ok(())
+            },
+        )?;
+
+        let status = match status {
+            Some(status) => status,
+            None => child.wait()?,
+        };
+        let current = read()?;
+        series.push(start.elapsed(), crate::cpus_delta(&previous, &current)?);
+
+        Ok((series, status))
+    }
+}
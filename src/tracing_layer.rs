@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tracing_core::field::Value;
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::{
+    read_thread_values, thread_delta, Aggregate, Counter, CounterValues, PerformanceCounters,
+    Region,
+};
+
+thread_local! {
+    /// The spans that are currently entered on this thread, with the counters at the time of entering
+    static ENTERED: RefCell<Vec<(Id, std::io::Result<CounterValues>, Instant)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A `tracing_subscriber::Layer` that counts events while a span is entered.
+///
+/// The counters of every entry of a span are summed, and when the span is closed the total is
+/// added to the aggregate for its name, see `CounterLayer::report`. Spans that declare fields
+/// named like a `Counter` (e.g. `cycles = tracing::field::Empty`) also get the running total
+/// recorded in those fields on every exit.
+#[derive(Clone, Default)]
+pub struct CounterLayer {
+    spans: Arc<Mutex<Vec<(&'static str, Aggregate)>>>,
+}
+
+/// The counters of a span, summed over all of its entries so far
+#[derive(Default)]
+struct SpanCounters {
+    counters: PerformanceCounters,
+    elapsed: core::time::Duration,
+}
+
+impl CounterLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The measurements of all closed spans so far, aggregated per span name
+    pub fn report(&self) -> Vec<Region> {
+        let spans = self.spans.lock().unwrap_or_else(|e| e.into_inner());
+
+        spans
+            .iter()
+            .map(|(name, aggregate)| Region {
+                name,
+                calls: aggregate.count(),
+                elapsed: aggregate.elapsed(),
+                run: aggregate.run(),
+            })
+            .collect()
+    }
+}
+
+impl<S> Layer<S> for CounterLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanCounters::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, _ctx: Context<'_, S>) {
+        let start_clock = Instant::now();
+        let start = read_thread_values();
+
+        ENTERED.with_borrow_mut(|entered| entered.push((id.clone(), start, start_clock)));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let end = read_thread_values();
+        let end_clock = Instant::now();
+
+        // spans are not required to be exited in the reverse order of entering
+        let Some((start, start_clock)) = ENTERED.with_borrow_mut(|entered| {
+            let position = entered.iter().rposition(|(entered, _, _)| entered == id)?;
+            let (_, start, start_clock) = entered.remove(position);
+            Some((start, start_clock))
+        }) else {
+            return;
+        };
+
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        // the span is left as it is when there are no counters
+        let Some(delta) = thread_delta(&start, &end) else {
+            return;
+        };
+
+        let total = {
+            let mut extensions = span.extensions_mut();
+            let Some(span_counters) = extensions.get_mut::<SpanCounters>() else {
+                return;
+            };
+
+            span_counters.counters += delta;
+            span_counters.elapsed += end_clock.duration_since(start_clock);
+            span_counters.counters
+        };
+
+        let fields = span.metadata().fields();
+        for counter in Counter::ALL {
+            let Some(field) = fields.field(counter.name()) else {
+                continue;
+            };
+
+            let value = counter.get(&total) as u64;
+            let values = [(&field, Some(&value as &dyn Value))];
+            let value_set = fields.value_set(&values);
+            let record = Record::new(&value_set);
+            tracing_core::dispatcher::get_default(|dispatch| dispatch.record(id, &record));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+
+        let Some(span_counters) = span.extensions_mut().remove::<SpanCounters>() else {
+            return;
+        };
+
+        let name = span.name();
+
+        let mut spans = self.spans.lock().unwrap_or_else(|e| e.into_inner());
+        match spans.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, aggregate)) => {
+                aggregate.push_counters(span_counters.counters, span_counters.elapsed)
+            }
+            None => {
+                let mut aggregate = Aggregate::default();
+                aggregate.push_counters(span_counters.counters, span_counters.elapsed);
+                spans.push((name, aggregate));
+            }
+        }
+    }
+}
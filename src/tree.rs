@@ -0,0 +1,269 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::{read_thread_values, thread_delta, Counter, CounterValues, PerformanceCounters};
+
+thread_local! {
+    static PROFILER: RefCell<Option<Profiler>> = const { RefCell::new(None) };
+}
+
+/// The call tree of the current thread, stored as an arena so that entering a region is cheap
+struct Profiler {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+    /// The regions that are currently entered, with the counters and time at which they were entered
+    stack: Vec<(usize, std::io::Result<CounterValues>, Instant)>,
+}
+
+struct Node {
+    name: &'static str,
+    children: Vec<usize>,
+    calls: usize,
+    elapsed: core::time::Duration,
+    inclusive: PerformanceCounters,
+}
+
+impl Profiler {
+    fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn enter(&mut self, name: &'static str) -> usize {
+        let siblings = match self.stack.last() {
+            Some((parent, _, _)) => &self.nodes[*parent].children,
+            None => &self.roots,
+        };
+
+        let index = match siblings.iter().find(|i| self.nodes[**i].name == name) {
+            Some(index) => *index,
+            None => {
+                let index = self.nodes.len();
+                self.nodes.push(Node {
+                    name,
+                    children: Vec::new(),
+                    calls: 0,
+                    elapsed: core::time::Duration::ZERO,
+                    inclusive: PerformanceCounters::default(),
+                });
+
+                match self.stack.last() {
+                    Some((parent, _, _)) => self.nodes[*parent].children.push(index),
+                    None => self.roots.push(index),
+                }
+
+                index
+            }
+        };
+
+        // read the counters last, so that the bookkeeping above is not attributed to the region
+        let start_clock = Instant::now();
+        let start = read_thread_values();
+        self.stack.push((index, start, start_clock));
+
+        index
+    }
+
+    fn exit(&mut self, index: usize) {
+        let end = read_thread_values();
+        let end_clock = Instant::now();
+
+        // a region that was closed with its parent below is not on the stack anymore
+        let Some(position) = self
+            .stack
+            .iter()
+            .rposition(|(entered, _, _)| *entered == index)
+        else {
+            return;
+        };
+
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if position + 1 != self.stack.len() && !WARNED.fetch_or(true, Ordering::Relaxed) {
+            eprintln!(
+                "Region {} exited before the regions entered in it; they end with it",
+                self.nodes[index].name
+            );
+        }
+
+        // regions must be exited in the reverse order of entering; the ones that are not end here
+        for (top, start, start_clock) in self.stack.drain(position..) {
+            // without counters the call is left out, rather than counted as zero events
+            let Some(delta) = thread_delta(&start, &end) else {
+                continue;
+            };
+            let node = &mut self.nodes[top];
+            node.calls += 1;
+            node.elapsed += end_clock.duration_since(start_clock);
+            node.inclusive += delta;
+        }
+    }
+
+    fn snapshot(&self, index: usize) -> CallNode {
+        let node = &self.nodes[index];
+        let children: Vec<_> = node.children.iter().map(|i| self.snapshot(*i)).collect();
+
+        let mut exclusive = node.inclusive;
+        for child in children.iter() {
+            exclusive -= child.inclusive;
+        }
+
+        CallNode {
+            name: node.name,
+            calls: node.calls,
+            elapsed: node.elapsed,
+            inclusive: node.inclusive,
+            exclusive,
+            children,
+        }
+    }
+}
+
+/// Guard for a region entered with `enter`; the region is exited when the guard is dropped
+pub struct Entered {
+    index: usize,
+    // the region lives in the call tree of the thread that entered it
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Enter a named region in the call tree of the current thread. Regions that are entered while
+/// another region is active become its children, so that nested phases form a tree.
+#[inline(always)]
+pub fn enter(name: &'static str) -> Entered {
+    let index =
+        PROFILER.with_borrow_mut(|profiler| profiler.get_or_insert_with(Profiler::new).enter(name));
+
+    Entered {
+        index,
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for Entered {
+    #[inline(always)]
+    fn drop(&mut self) {
+        PROFILER.with_borrow_mut(|profiler| {
+            if let Some(profiler) = profiler {
+                profiler.exit(self.index);
+            }
+        })
+    }
+}
+
+/// A named region in the call tree, with the counters of all of its calls combined.
+/// The inclusive counters include those of the children, the exclusive counters do not.
+#[derive(Debug, Clone)]
+pub struct CallNode {
+    pub name: &'static str,
+    pub calls: usize,
+    pub elapsed: core::time::Duration,
+    pub inclusive: PerformanceCounters,
+    pub exclusive: PerformanceCounters,
+    pub children: Vec<CallNode>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallTree {
+    pub roots: Vec<CallNode>,
+}
+
+/// The call tree of the regions entered on the current thread so far
+pub fn call_tree() -> CallTree {
+    PROFILER.with_borrow(|profiler| match profiler {
+        None => CallTree::default(),
+        Some(profiler) => CallTree {
+            roots: profiler
+                .roots
+                .iter()
+                .map(|i| profiler.snapshot(*i))
+                .collect(),
+        },
+    })
+}
+
+impl CallTree {
+    /// Write the tree in the folded stacks format (`load;parse;validate 1234`) that flamegraph
+    /// tools accept, using the exclusive value of the given counter.
+    pub fn write_folded(
+        &self,
+        w: &mut impl std::io::Write,
+        counter: Counter,
+    ) -> std::io::Result<()> {
+        fn go(
+            w: &mut impl std::io::Write,
+            counter: Counter,
+            stack: &mut String,
+            node: &CallNode,
+        ) -> std::io::Result<()> {
+            let len = stack.len();
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            stack.push_str(node.name);
+
+            let value = counter.get(&node.exclusive).max(0.0) as u64;
+            if value > 0 {
+                writeln!(w, "{stack} {value}")?;
+            }
+
+            for child in node.children.iter() {
+                go(w, counter, stack, child)?;
+            }
+
+            stack.truncate(len);
+
+            Ok(())
+        }
+
+        let mut stack = String::new();
+        for root in self.roots.iter() {
+            go(w, counter, &mut stack, root)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CallTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn go(f: &mut std::fmt::Formatter<'_>, depth: usize, node: &CallNode) -> std::fmt::Result {
+            let indent = 2 * depth;
+            let width = 32usize.saturating_sub(indent);
+
+            write!(f, "{:indent$}{:<width$} {:>8}", "", node.name, node.calls)?;
+            for counter in [Counter::Cycles, Counter::Instructions, Counter::Branches] {
+                write!(
+                    f,
+                    " {:>14.0} {:>14.0}",
+                    counter.get(&node.inclusive),
+                    counter.get(&node.exclusive)
+                )?;
+            }
+            writeln!(f)?;
+
+            for child in node.children.iter() {
+                go(f, depth + 1, child)?;
+            }
+
+            Ok(())
+        }
+
+        write!(f, "{:<32} {:>8}", "region", "calls")?;
+        for counter in [Counter::Cycles, Counter::Instructions, Counter::Branches] {
+            let inclusive = format!("{} (incl)", counter.name());
+            let exclusive = format!("{} (excl)", counter.name());
+            write!(f, " {:>14} {:>14}", inclusive, exclusive)?;
+        }
+        writeln!(f)?;
+
+        for root in self.roots.iter() {
+            go(f, 0, root)?;
+        }
+
+        Ok(())
+    }
+}